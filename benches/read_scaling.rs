@@ -0,0 +1,73 @@
+//! Read throughput against one store as the number of reader threads grows,
+//! with a writer overwriting keys the whole time.
+//!
+//! Run with `cargo bench --bench read_scaling`.
+use bitcaskplus::{BitCaskPlus, Options};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+const RUN_TIME: Duration = Duration::from_millis(500);
+
+fn main() -> bitcaskplus::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BitCaskPlus::open_with(temp_dir.path(), Options::new())?;
+    for key_id in 0..KEYS {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = 1;
+    while threads <= cores.max(32) {
+        let ops = run(&store, threads)?;
+        println!(
+            "{:>3} reader threads: {:>12.0} gets/s",
+            threads,
+            ops as f64 / RUN_TIME.as_secs_f64()
+        );
+        threads *= 2;
+    }
+    Ok(())
+}
+
+/// Number of gets `threads` readers complete in `RUN_TIME`.
+fn run(store: &BitCaskPlus, threads: usize) -> bitcaskplus::Result<u64> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, stop) = (store.clone(), stop.clone());
+        thread::spawn(move || -> bitcaskplus::Result<()> {
+            let mut key_id = 0;
+            while !stop.load(Ordering::Relaxed) {
+                store.set(format!("key{}", key_id % KEYS), "updated".to_owned())?;
+                key_id += 1;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..threads)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> bitcaskplus::Result<u64> {
+                let start = Instant::now();
+                let mut ops = 0;
+                while start.elapsed() < RUN_TIME {
+                    let key = format!("key{}", (ops as usize * 7919 + thread_id) % KEYS);
+                    assert!(store.get(&key)?.is_some());
+                    ops += 1;
+                }
+                Ok(ops)
+            })
+        })
+        .collect();
+
+    let mut total = 0;
+    for reader in readers {
+        total += reader.join().unwrap()?;
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap()?;
+    Ok(total)
+}
@@ -0,0 +1,363 @@
+//! On-disk layout of the `.db` data files.
+//!
+//! Every data file starts with [`MAGIC`] and the little-endian format version,
+//! followed by records laid out back to back:
+//!
+//! CRC(4) + Timestamp(8) + Flags(1) + KeyLen(4) + ValueLen(4) + Key + [Expiry(8)] + [Seqno(8)] + Value
+//!
+//! The CRC covers everything after itself. The flags tell which optional
+//! fields follow the key: the little-endian millisecond timestamp at which
+//! the value expires, then the sequence number of the record, both counted in
+//! ValueLen. A record with the batch flag frames a write batch instead: it has
+//! no key, and the records of the batch as its value, so the one CRC covers
+//! the whole batch. A timestamp of [`UNKNOWN_TIMESTAMP`] marks a record
+//! migrated from a legacy file, whose write time was never recorded. Files
+//! without the magic number were
+//! written by the JSON format ([`LEGACY_VERSION`]), whose records are
+//! CRC(4) + Len(8) + Json(N). They stay readable and are rewritten in the
+//! current format the next time compaction merges them.
+//!
+//! Hint files start with [`HINT_MAGIC`] and [`HINT_VERSION`], followed by one
+//! entry per record of their data file, tombstones included:
+//!
+//! CRC(4) + Flags(1) + KeyLen(4) + Pos(8) + Len(8) + Key + [Expiry(8)] + [Seqno(8)]
+//!
+//! The flags are those of the records. The optional fields are present when
+//! the expiry and sequence number flags are set. Hints without the magic number were written as
+//! JSON and are ignored, the data file is scanned instead.
+use crate::{Command, CommandPos, Error, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: [u8; 4] = *b"BCP+";
+pub const LEGACY_VERSION: u32 = 0;
+pub const FORMAT_VERSION: u32 = 1;
+/// Timestamp of records whose write time is not known.
+pub const UNKNOWN_TIMESTAMP: u64 = 0;
+pub const FILE_HEADER_LEN: u64 = 8;
+pub const HINT_MAGIC: [u8; 4] = *b"BCPh";
+pub const HINT_VERSION: u32 = 1;
+pub const HINT_ENTRY_HEADER_LEN: usize = 25;
+pub const RECORD_HEADER_LEN: u64 = 21;
+const LEGACY_HEADER_LEN: u64 = 12;
+
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_BATCH: u8 = 2;
+const FLAG_EXPIRES: u8 = 4;
+const FLAG_SEQNO: u8 = 8;
+
+/// Header fields of a record that are not part of its [`Command`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// Write time in milliseconds since the Unix epoch, `None` for records of
+    /// legacy files.
+    pub timestamp: Option<u64>,
+    pub seqno: Option<u64>,
+}
+
+/// Record payload of [`LEGACY_VERSION`] files, which only held UTF-8 text.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Self {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            LegacyCommand::Remove { key } => Command::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+pub fn file_header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    header[0..4].copy_from_slice(&MAGIC);
+    header[4..8].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Detects the format version of a data file from its header.
+pub fn read_version(file: &File) -> Result<u32> {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    match file.read_exact_at(&mut header, 0) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(LEGACY_VERSION),
+        Err(e) => return Err(e.into()),
+    }
+    if header[0..4] != MAGIC {
+        return Ok(LEGACY_VERSION);
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    Ok(version)
+}
+
+/// Offset of the first record in a file of the given version.
+pub fn data_start(version: u32) -> u64 {
+    if version == LEGACY_VERSION {
+        0
+    } else {
+        FILE_HEADER_LEN
+    }
+}
+
+pub fn header_len(version: u32) -> u64 {
+    if version == LEGACY_VERSION {
+        LEGACY_HEADER_LEN
+    } else {
+        RECORD_HEADER_LEN
+    }
+}
+
+/// Total length of the record starting with `header`.
+///
+/// Returns `None` when a corrupted legacy length does not fit in a `u64`.
+pub fn record_len(version: u32, header: &[u8]) -> Option<u64> {
+    if version == LEGACY_VERSION {
+        LEGACY_HEADER_LEN.checked_add(u64::from_le_bytes(header[4..12].try_into().unwrap()))
+    } else {
+        let key_len = u32::from_le_bytes(header[13..17].try_into().unwrap()) as u64;
+        let value_len = u32::from_le_bytes(header[17..21].try_into().unwrap()) as u64;
+        Some(RECORD_HEADER_LEN + key_len + value_len)
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn encode(cmd: &Command, timestamp: u64, seqno: Option<u64>) -> Vec<u8> {
+    let (mut flags, key, value, expires_at) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        } => {
+            let flags = expires_at.map_or(0, |_| FLAG_EXPIRES);
+            (flags, key.as_slice(), value.as_slice(), *expires_at)
+        }
+        Command::Remove { key } => (FLAG_TOMBSTONE, key.as_slice(), &[][..], None),
+    };
+    let mut prefix = Vec::with_capacity(16);
+    if let Some(expires_at) = expires_at {
+        prefix.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if let Some(seqno) = seqno {
+        flags |= FLAG_SEQNO;
+        prefix.extend_from_slice(&seqno.to_le_bytes());
+    }
+    let value_len = prefix.len() + value.len();
+    let mut buffer = Vec::with_capacity(RECORD_HEADER_LEN as usize + key.len() + value_len);
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&timestamp.to_le_bytes());
+    buffer.push(flags);
+    buffer.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&(value_len as u32).to_le_bytes());
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(&prefix);
+    buffer.extend_from_slice(value);
+    let checksum = crc32fast::hash(&buffer[4..]);
+    buffer[0..4].copy_from_slice(&checksum.to_le_bytes());
+    buffer
+}
+
+/// Verifies the checksum of a whole record and decodes it.
+///
+/// Returns `None` when the record is corrupted.
+pub fn decode(version: u32, buffer: &[u8]) -> Option<Command> {
+    decode_record(version, buffer).map(|(cmd, _)| cmd)
+}
+
+/// Like [`decode`], but also returns the header fields of the record.
+pub fn decode_record(version: u32, buffer: &[u8]) -> Option<(Command, RecordMeta)> {
+    let expected_crc = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+    let body = if version == LEGACY_VERSION {
+        &buffer[LEGACY_HEADER_LEN as usize..]
+    } else {
+        &buffer[4..]
+    };
+    if crc32fast::hash(body) != expected_crc {
+        return None;
+    }
+    if version == LEGACY_VERSION {
+        let cmd = serde_json::from_slice::<LegacyCommand>(body).ok()?;
+        return Some((cmd.into(), RecordMeta::default()));
+    }
+
+    let timestamp = u64::from_le_bytes(buffer[4..12].try_into().unwrap());
+    let flags = buffer[12];
+    let key_len = u32::from_le_bytes(buffer[13..17].try_into().unwrap()) as usize;
+    let key_end = RECORD_HEADER_LEN as usize + key_len;
+    let key = buffer[RECORD_HEADER_LEN as usize..key_end].to_vec();
+    // Optional fields sit between the key and the value.
+    let mut value_start = key_end;
+    let mut take_u64 = |present: bool| -> Option<Option<u64>> {
+        if !present {
+            return Some(None);
+        }
+        let field = buffer.get(value_start..value_start + 8)?;
+        value_start += 8;
+        Some(Some(u64::from_le_bytes(field.try_into().unwrap())))
+    };
+    let expires_at = take_u64(flags & FLAG_EXPIRES != 0)?;
+    let seqno = take_u64(flags & FLAG_SEQNO != 0)?;
+    let meta = RecordMeta {
+        timestamp: (timestamp != UNKNOWN_TIMESTAMP).then_some(timestamp),
+        seqno,
+    };
+    if flags & FLAG_TOMBSTONE != 0 {
+        Some((Command::Remove { key }, meta))
+    } else {
+        let value = buffer[value_start..].to_vec();
+        Some((
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            },
+            meta,
+        ))
+    }
+}
+
+/// Frames already encoded records as one batch record.
+pub fn encode_batch(records: &[Vec<u8>], timestamp: u64) -> Vec<u8> {
+    let value_len: usize = records.iter().map(Vec::len).sum();
+    let mut buffer = Vec::with_capacity(RECORD_HEADER_LEN as usize + value_len);
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&timestamp.to_le_bytes());
+    buffer.push(FLAG_BATCH);
+    buffer.extend_from_slice(&0u32.to_le_bytes());
+    buffer.extend_from_slice(&(value_len as u32).to_le_bytes());
+    for record in records {
+        buffer.extend_from_slice(record);
+    }
+    let checksum = crc32fast::hash(&buffer[4..]);
+    buffer[0..4].copy_from_slice(&checksum.to_le_bytes());
+    buffer
+}
+
+/// Whether the record starting with `header` frames a batch.
+pub fn is_batch(version: u32, header: &[u8]) -> bool {
+    // Legacy headers are too short to hold flags.
+    version != LEGACY_VERSION && header[12] & FLAG_BATCH != 0
+}
+
+/// Verifies a whole batch frame: its own checksum, and that it holds nothing
+/// but intact records back to back.
+pub fn verify_batch(version: u32, buffer: &[u8]) -> bool {
+    let expected_crc = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+    if crc32fast::hash(&buffer[4..]) != expected_crc {
+        return false;
+    }
+    let mut records = &buffer[RECORD_HEADER_LEN as usize..];
+    while !records.is_empty() {
+        if records.len() < RECORD_HEADER_LEN as usize || is_batch(version, records) {
+            return false;
+        }
+        let len = record_len(version, records).unwrap_or(u64::MAX) as usize;
+        if len > records.len() || decode(version, &records[..len]).is_none() {
+            return false;
+        }
+        records = &records[len..];
+    }
+    true
+}
+
+pub fn hint_header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    header[0..4].copy_from_slice(&HINT_MAGIC);
+    header[4..8].copy_from_slice(&HINT_VERSION.to_le_bytes());
+    header
+}
+
+/// Encodes the hint entry of the record at `pos`, a tombstone if `tombstone`
+/// is set. Its file number is not stored, it is the one of the data file next
+/// to the hint.
+pub fn encode_hint(key: &[u8], pos: &CommandPos, tombstone: bool) -> Vec<u8> {
+    let mut flags = if tombstone { FLAG_TOMBSTONE } else { 0 };
+    let mut suffix = Vec::with_capacity(16);
+    if let Some(expires_at) = pos.expires_at {
+        flags |= FLAG_EXPIRES;
+        suffix.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if let Some(seqno) = pos.seqno {
+        flags |= FLAG_SEQNO;
+        suffix.extend_from_slice(&seqno.to_le_bytes());
+    }
+    let mut buffer = Vec::with_capacity(HINT_ENTRY_HEADER_LEN + key.len() + suffix.len());
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.push(flags);
+    buffer.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&pos.pos.to_le_bytes());
+    buffer.extend_from_slice(&pos.len.to_le_bytes());
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(&suffix);
+    let checksum = crc32fast::hash(&buffer[4..]);
+    buffer[0..4].copy_from_slice(&checksum.to_le_bytes());
+    buffer
+}
+
+/// Total length of the hint entry starting with `header`.
+pub fn hint_entry_len(header: &[u8]) -> usize {
+    let key_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    let optional = [FLAG_EXPIRES, FLAG_SEQNO]
+        .iter()
+        .filter(|&&flag| header[4] & flag != 0)
+        .count();
+    HINT_ENTRY_HEADER_LEN + key_len + 8 * optional
+}
+
+/// Verifies the checksum of a whole hint entry and decodes it, with file
+/// number 0, along with whether it is a tombstone.
+///
+/// Returns `None` when the entry is corrupted.
+pub fn decode_hint(buffer: &[u8]) -> Option<(Vec<u8>, CommandPos, bool)> {
+    let expected_crc = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+    if crc32fast::hash(&buffer[4..]) != expected_crc {
+        return None;
+    }
+    let flags = buffer[4];
+    let u64_at = |offset: usize| u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+    let key_len = u32::from_le_bytes(buffer[5..9].try_into().unwrap()) as usize;
+    let key_end = HINT_ENTRY_HEADER_LEN + key_len;
+    let key = buffer[HINT_ENTRY_HEADER_LEN..key_end].to_vec();
+    let mut offset = key_end;
+    let mut take_u64 = |flag: u8| {
+        (flags & flag != 0).then(|| {
+            let value = u64_at(offset);
+            offset += 8;
+            value
+        })
+    };
+    let expires_at = take_u64(FLAG_EXPIRES);
+    let seqno = take_u64(FLAG_SEQNO);
+    Some((
+        key,
+        CommandPos {
+            file_num: 0,
+            pos: u64_at(9),
+            len: u64_at(17),
+            expires_at,
+            seqno,
+        },
+        flags & FLAG_TOMBSTONE != 0,
+    ))
+}
@@ -1,2 +1,6 @@
+pub mod changes;
 pub mod compaction;
+pub mod compactor;
+pub mod read_only;
 pub mod reader;
+pub mod scan;
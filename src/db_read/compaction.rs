@@ -1,119 +1,565 @@
-use crate::{BitCaskPlus, CommandPos, DataReader, Result};
-use std::collections::HashMap;
+use crate::db_format;
+use crate::db_write::writer::ActiveFile;
+use crate::{
+    BitCaskPlus, Command, CommandPos, DataReader, Error, FileStats, Keydir, Options, Result,
+};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufWriter, Seek, Write};
+use std::io::{self, BufWriter, Write};
 use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 fn migrate_entry(
     reader: &DataReader,
     pos: u64,
     len: u64,
     new_f: &mut BufWriter<File>,
-) -> Result<()> {
+) -> Result<u64> {
     let mut buffer = vec![0u8; len as usize];
     reader.file.read_exact_at(&mut buffer, pos)?;
+    // Binary records are copied as they are, batch frames included.
+    // Legacy records never had a write time, they do not get the merge time.
+    if reader.version == db_format::LEGACY_VERSION {
+        let cmd = db_format::decode(reader.version, &buffer).ok_or(Error::Corruption {
+            file: reader.file_num,
+            offset: pos,
+        })?;
+        buffer = db_format::encode(&cmd, db_format::UNKNOWN_TIMESTAMP, None);
+    }
     new_f.write_all(&buffer)?;
+    Ok(buffer.len() as u64)
+}
+
+/// Writes the hint of a merge output from its `entries` in file order, each
+/// flagged if it is a tombstone.
+fn write_hint(path: &Path, gen_num: u64, entries: &[(Vec<u8>, CommandPos, bool)]) -> Result<()> {
+    let hint_path = path.join(format!("{}.db.hint", gen_num));
+    let hint_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&hint_path)?;
+    let mut hint_writer = BufWriter::new(hint_file);
+    hint_writer.write_all(&db_format::hint_header())?;
+    for (key, pos, tombstone) in entries {
+        hint_writer.write_all(&db_format::encode_hint(key, pos, *tombstone))?;
+    }
+    hint_writer.flush()?;
+    hint_writer.get_ref().sync_all()?;
     Ok(())
 }
 
-impl BitCaskPlus {
-    pub fn compaction(&mut self) -> Result<()> {
-        let compaction_gen = self.cur_gen + 1;
-        self.cur_gen += 2;
-        {
-            let mut w_lock = self.writer.lock().unwrap();
-            w_lock.flush()?;
-            let new_file =
-                crate::new_log_file(&self.path, self.cur_gen, &mut self.readers.write().unwrap())?;
-            *w_lock = BufWriter::new(new_file);
+/// Record of a merge in progress, kept as `{compaction_gen}.merge` until
+/// every input is deleted.
+///
+/// `open` discards the output of a merge that is not `complete` and finishes
+/// deleting the inputs of one that is.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    inputs: Vec<u64>,
+    complete: bool,
+}
+
+fn manifest_path(path: &Path, compaction_gen: u64) -> PathBuf {
+    path.join(format!("{}.merge", compaction_gen))
+}
+
+fn write_manifest(path: &Path, compaction_gen: u64, manifest: &Manifest) -> Result<()> {
+    write_durably(
+        path,
+        &manifest_path(path, compaction_gen),
+        &serde_json::to_vec(manifest)?,
+    )
+}
+
+/// Holds the [`SeqnoMark`] of the last merge.
+const SEQNO_FILE: &str = "SEQNO";
+
+/// Sequence numbers a merge may drop the records of, so `open` cannot rely on
+/// the surviving records to find them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub(crate) struct SeqnoMark {
+    /// Highest sequence number handed out before the merge.
+    pub(crate) last: u64,
+    /// Highest sequence number of a remove dropped by any merge so far.
+    pub(crate) compacted: u64,
+}
+
+fn write_seqno_mark(path: &Path, mark: SeqnoMark) -> Result<()> {
+    write_durably(path, &path.join(SEQNO_FILE), &serde_json::to_vec(&mark)?)
+}
+
+/// Mark recorded by the last merge, all zero if nothing was merged yet.
+pub(crate) fn read_seqno_mark(path: &Path) -> Result<SeqnoMark> {
+    match fs::read(path.join(SEQNO_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SeqnoMark::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces `file_path` through a synced temporary file, so it is never torn.
+fn write_durably(path: &Path, file_path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_name = file_path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(data)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, file_path)?;
+    sync_dir(path)
+}
+
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+fn remove_generation(path: &Path, gen_num: u64) -> Result<()> {
+    for file_name in [format!("{}.db", gen_num), format!("{}.db.hint", gen_num)] {
+        match fs::remove_file(path.join(file_name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Settles merges interrupted by a crash, before the keydir is rebuilt.
+///
+/// Returns the generations that must not be loaded. That is only ever
+/// non-empty when `read_only` forbids cleaning them up.
+pub(crate) fn recover_merges(path: &Path, read_only: bool) -> Result<HashSet<u64>> {
+    let mut skipped = HashSet::new();
+    for entry in fs::read_dir(path)? {
+        let manifest_path = entry?.path();
+        if manifest_path.extension() != Some("merge".as_ref()) {
+            continue;
         }
-        let compact_file = crate::new_log_file(
-            &self.path,
-            compaction_gen,
-            &mut self.readers.write().unwrap(),
-        )?;
-
-        //TODO: Got mismatch error when introducing hint file.
-        /*let hint_log_path = self.path.join(format!("{}.db.hint", self.cur_gen));
-        let mut hint_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(&hint_log_path)?;
-        hint_file.seek(io::SeekFrom::End(0))?;
-        let mut hint_writer = BufWriter::new(hint_file);*/
-
-        let mut compact_writer = BufWriter::new(compact_file);
-
-        let mut new_pos = 0;
-        let mut new_map = HashMap::new();
-
-        let entries: Vec<(String, CommandPos)> = {
-            let m = self.map.read().unwrap();
-            m.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        let compaction_gen = match manifest_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            Some(compaction_gen) => compaction_gen,
+            None => continue,
+        };
+        // The manifest is renamed into place, so it is never torn. It is gone
+        // when a writer finished the merge since the listing.
+        let data = match fs::read(&manifest_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            data => data?,
         };
+        let manifest: Manifest = serde_json::from_slice(&data)?;
+        let stale_gens = if manifest.complete {
+            manifest.inputs
+        } else {
+            vec![compaction_gen]
+        };
+        if read_only {
+            skipped.extend(stale_gens);
+            continue;
+        }
+        for stale_gen in stale_gens {
+            remove_generation(path, stale_gen)?;
+        }
+        sync_dir(path)?;
+        fs::remove_file(&manifest_path)?;
+    }
+    Ok(skipped)
+}
 
-        for (key, pos_info) in &entries {
-            let readers = self.readers.read().unwrap();
-            let reader = readers
-                .get(&pos_info.file_num)
-                .expect("Can not find db file");
-            // get checksum, len and data
-            migrate_entry(&reader, pos_info.pos, pos_info.len, &mut compact_writer)?;
-            new_map.insert(
-                key.clone(),
-                CommandPos {
-                    file_num: compaction_gen,
-                    pos: new_pos,
-                    len: pos_info.len,
-                },
-            );
+/// Points in a merge after which a test can make it fail, as if the process
+/// had crashed there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MergeStep {
+    Started,
+    OutputWritten,
+    Committed,
+    InputDeleted,
+}
 
-            new_pos += pos_info.len;
-        }
-        compact_writer.flush()?;
+#[cfg(test)]
+thread_local! {
+    pub(crate) static CRASH_AFTER: std::cell::Cell<Option<MergeStep>> =
+        const { std::cell::Cell::new(None) };
+}
+
+fn reached(_step: MergeStep) -> Result<()> {
+    #[cfg(test)]
+    if CRASH_AFTER.with(|c| c.get()) == Some(_step) {
+        return Err(Error::Io(io::Error::other("simulated crash")));
+    }
+    Ok(())
+}
+
+/// Handles on the store state that a merge reads and swaps.
+#[derive(Debug, Clone)]
+pub(crate) struct MergeContext {
+    pub(crate) path: PathBuf,
+    pub(crate) keydir: Arc<ArcSwap<Keydir>>,
+    pub(crate) writer: Arc<Mutex<Option<ActiveFile>>>,
+    pub(crate) stats: Arc<Mutex<HashMap<u64, FileStats>>>,
+    /// [`SeqnoMark::compacted`] of the store.
+    pub(crate) compacted_seqno: Arc<AtomicU64>,
+    /// Generations `open` found damaged. They are never merged, which would
+    /// delete the records behind the damage.
+    pub(crate) damaged: HashSet<u64>,
+}
 
-        let stale_gens: Vec<u64> = {
-            let readers = self.readers.read().unwrap();
+impl MergeContext {
+    /// Every generation below `compaction_gen`.
+    pub(crate) fn sealed(&self, compaction_gen: u64) -> Vec<u64> {
+        let mut gens: Vec<u64> = self
+            .keydir
+            .load()
+            .readers
+            .keys()
+            .filter(|&&g| g < compaction_gen)
+            .cloned()
+            .collect();
+        gens.sort_unstable();
+        gens
+    }
+
+    /// Sealed generations a merge may rewrite, which leaves out damaged ones.
+    pub(crate) fn mergeable(&self, compaction_gen: u64) -> Vec<u64> {
+        let mut gens = self.sealed(compaction_gen);
+        gens.retain(|g| !self.damaged.contains(g));
+        gens
+    }
 
-            readers
-                .keys()
-                .filter(|&&g| g < compaction_gen)
-                .cloned()
-                .collect()
+    /// Generations below `compaction_gen` holding enough garbage to be worth
+    /// rewriting, going by `frag_threshold` and `dead_bytes_threshold`.
+    pub(crate) fn select(&self, compaction_gen: u64, options: &Options) -> Vec<u64> {
+        let stats = self.stats.lock().unwrap();
+        self.mergeable(compaction_gen)
+            .into_iter()
+            .filter(|g| worth_merging(&stats.get(g).cloned().unwrap_or_default(), options))
+            .collect()
+    }
+}
+
+fn worth_merging(file_stats: &FileStats, options: &Options) -> bool {
+    file_stats.live_bytes == 0
+        || file_stats.fragmentation() > options.frag_threshold
+        || file_stats.dead_bytes > options.dead_bytes_threshold
+}
+
+/// Copies every live record of the `inputs` generations into
+/// `compaction_gen`, points the keydir at the copies and deletes the inputs.
+///
+/// `compaction_gen` must be above every sealed generation and below the
+/// active file, so writes can keep going while this runs. A [`Manifest`]
+/// makes the merge atomic across crashes.
+pub(crate) fn merge(ctx: &MergeContext, inputs: &[u64], compaction_gen: u64) -> Result<()> {
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    let path = ctx.path.as_path();
+    let mut manifest = Manifest {
+        inputs: inputs.to_vec(),
+        complete: false,
+    };
+    write_manifest(path, compaction_gen, &manifest)?;
+    let compact_file = {
+        let _guard = ctx.writer.lock().unwrap();
+        crate::update_keydir(&ctx.keydir, |keydir| {
+            crate::new_log_file(path, compaction_gen, &mut keydir.readers)
+        })?
+    };
+
+    let mut compact_writer = BufWriter::new(compact_file);
+
+    let mut new_pos = db_format::FILE_HEADER_LEN;
+    let mut new_map = HashMap::new();
+    let mut new_stats = FileStats::default();
+    let mut hint = Vec::new();
+
+    // A tombstone can only go once every older generation is merged as well,
+    // otherwise the records it deletes come back on the next open.
+    let oldest_kept = ctx
+        .sealed(compaction_gen)
+        .into_iter()
+        .find(|g| !inputs.contains(g));
+    // The latest tombstone of each removed key, and whether it is carried.
+    let mut tombstones = HashMap::new();
+    for &gen_num in inputs {
+        let no_tombstones = {
+            let stats = ctx.stats.lock().unwrap();
+            stats.get(&gen_num).is_some_and(|s| s.tombstones == 0)
+        };
+        if no_tombstones {
+            continue;
+        }
+        let carry = oldest_kept.is_some_and(|kept| kept < gen_num);
+        let mut reader = match ctx.keydir.load().readers.get(&gen_num) {
+            Some(reader) => reader.clone(),
+            None => continue,
         };
+        reader.cursor = db_format::data_start(reader.version);
+        for (cmd, cmd_pos) in reader.map_while(Result::ok) {
+            if let Command::Remove { key } = cmd
+                && !ctx.keydir.load().map.contains_key(&key)
+            {
+                tombstones.insert(key, (cmd_pos, carry));
+            }
+        }
+    }
+    // `changes_since` can no longer return the removes that are dropped.
+    let mut dropped_seqno = 0;
+    for (key, (cmd_pos, carry)) in tombstones {
+        if !carry {
+            dropped_seqno = dropped_seqno.max(cmd_pos.seqno.unwrap_or(0));
+            continue;
+        }
+        let reader = ctx
+            .keydir
+            .load()
+            .readers
+            .get(&cmd_pos.file_num)
+            .cloned()
+            .ok_or(Error::FileNotFound(cmd_pos.file_num))?;
+        let len = migrate_entry(&reader, cmd_pos.pos, cmd_pos.len, &mut compact_writer)?;
+        let hint_pos = CommandPos {
+            file_num: compaction_gen,
+            pos: new_pos,
+            len,
+            expires_at: None,
+            seqno: cmd_pos.seqno,
+        };
+        hint.push((key, hint_pos, true));
+        new_pos += len;
+        new_stats.dead_bytes += len;
+        new_stats.tombstones += 1;
+        new_stats.max_seqno = new_stats.max_seqno.max(cmd_pos.seqno.unwrap_or(0));
+    }
 
-        for stale_gen in stale_gens {
-            self.readers.write().unwrap().remove(&stale_gen);
-            let path = self.path.join(format!("{}.db", stale_gen));
-            match fs::remove_file(&path) {
-                Ok(_) => println!("Successfully deleted: {:?}", path),
-                Err(e) => println!("Failed to delete {:?}: {}", path, e),
+    let snapshot = ctx.keydir.load_full();
+    let entries = snapshot
+        .map
+        .iter()
+        .filter(|(_, v)| inputs.contains(&v.file_num));
+
+    // Expired values are dropped. Like a remove, that takes a tombstone while
+    // an older generation may still hold the key.
+    let now = db_format::timestamp();
+    let mut expired = Vec::new();
+    for (key, pos_info) in entries {
+        if !pos_info.is_live(now) {
+            if oldest_kept.is_some_and(|kept| kept < pos_info.file_num) {
+                // The value was gone from the moment it expired.
+                let record = db_format::encode(
+                    &Command::Remove { key: key.clone() },
+                    pos_info.expires_at.unwrap_or_else(db_format::timestamp),
+                    pos_info.seqno,
+                );
+                compact_writer.write_all(&record)?;
+                let hint_pos = CommandPos {
+                    file_num: compaction_gen,
+                    pos: new_pos,
+                    len: record.len() as u64,
+                    expires_at: None,
+                    seqno: pos_info.seqno,
+                };
+                hint.push((key.clone(), hint_pos, true));
+                new_pos += record.len() as u64;
+                new_stats.dead_bytes += record.len() as u64;
+                new_stats.tombstones += 1;
+                new_stats.max_seqno = new_stats.max_seqno.max(pos_info.seqno.unwrap_or(0));
             }
+            expired.push((key, pos_info));
+            continue;
         }
+        let reader = snapshot
+            .readers
+            .get(&pos_info.file_num)
+            .ok_or(Error::FileNotFound(pos_info.file_num))?;
+        // get checksum, len and data
+        let len = migrate_entry(reader, pos_info.pos, pos_info.len, &mut compact_writer)?;
+        let new_pos_info = CommandPos {
+            file_num: compaction_gen,
+            pos: new_pos,
+            len,
+            expires_at: pos_info.expires_at,
+            seqno: pos_info.seqno,
+        };
+        hint.push((key.clone(), new_pos_info.clone(), false));
+        new_map.insert(key.clone(), new_pos_info);
 
-        {
-            let mut m_lock = self.map.write().unwrap();
+        new_pos += len;
+    }
+    reached(MergeStep::Started)?;
+    compact_writer.flush()?;
+    compact_writer.get_ref().sync_all()?;
+    write_hint(path, compaction_gen, &hint)?;
+    reached(MergeStep::OutputWritten)?;
+
+    // The inputs may hold the highest sequence numbers, remember them first.
+    let last_seqno = {
+        let active = ctx.writer.lock().unwrap();
+        active.as_ref().map_or(0, |active| active.next_seqno - 1)
+    };
+    let compacted_seqno = ctx
+        .compacted_seqno
+        .load(Ordering::SeqCst)
+        .max(dropped_seqno);
+    write_seqno_mark(
+        path,
+        SeqnoMark {
+            last: last_seqno,
+            compacted: compacted_seqno,
+        },
+    )?;
+    manifest.complete = true;
+    write_manifest(path, compaction_gen, &manifest)?;
+    reached(MergeStep::Committed)?;
+
+    // Point the keydir at the copies and drop the merged readers in one
+    // version, so a `get` never finds a position in a generation that is gone.
+    {
+        let _guard = ctx.writer.lock().unwrap();
+        let mut stats = ctx.stats.lock().unwrap();
+        // Raised first, a `changes_since` that finds the new version sees it.
+        ctx.compacted_seqno
+            .fetch_max(compacted_seqno, Ordering::SeqCst);
+        crate::update_keydir(&ctx.keydir, |keydir| {
             for (key, new_pos_info) in &new_map {
-                if let Some(current_pos) = m_lock.get(key) {
-                    if current_pos.file_num < compaction_gen {
-                        m_lock.insert(key.clone(), new_pos_info.clone());
+                new_stats.max_seqno = new_stats.max_seqno.max(new_pos_info.seqno.unwrap_or(0));
+                // Keys overwritten or removed since the snapshot stay as they are.
+                match keydir.map.get_mut(key) {
+                    Some(current_pos) if inputs.contains(&current_pos.file_num) => {
+                        *current_pos = new_pos_info.clone();
+                        new_stats.live_bytes += new_pos_info.len;
                     }
+                    _ => new_stats.dead_bytes += new_pos_info.len,
                 }
             }
-
-            //TODO: Got mismatch error when introducing hint file.
-            /*for (key, pos) in &new_map {
-                let entry_data = serde_json::to_string(&(key, pos))
-                    .map_err(|e| io::Error::other(e.to_string()))?;
-                let entry_data_len = entry_data.len() as u32;
-                hint_writer.write_all(&entry_data_len.to_le_bytes())?;
-                hint_writer.write_all(entry_data.as_bytes())?;
+            for (key, pos_info) in &expired {
+                if keydir.map.get(*key) == Some(*pos_info) {
+                    keydir.map.remove(*key);
+                }
+            }
+            for stale_gen in inputs {
+                stats.remove(stale_gen);
+                keydir.readers.remove(stale_gen);
             }
-            hint_writer.flush()?;*/
+        });
+        stats.insert(compaction_gen, new_stats);
+    }
+
+    for &stale_gen in inputs {
+        remove_generation(path, stale_gen)?;
+        reached(MergeStep::InputDeleted)?;
+    }
+    sync_dir(path)?;
+    fs::remove_file(manifest_path(path, compaction_gen))?;
+
+    Ok(())
+}
+
+impl BitCaskPlus {
+    /// Seals the active file and reserves the generation the merge writes to,
+    /// right below the new active file.
+    fn begin_compaction(&self, active: &mut ActiveFile) -> Result<u64> {
+        let compaction_gen = active.gen_num + 1;
+        self.rotate(active, compaction_gen + 1)?;
+        self.uncompacted.store(0, Ordering::Relaxed);
+        Ok(compaction_gen)
+    }
+
+    /// Whether any generation, the active file included, holds enough
+    /// garbage for [`MergeContext::select`] to pick it.
+    fn has_merge_candidates(&self) -> bool {
+        let stats = self.stats.lock().unwrap();
+        self.keydir
+            .load()
+            .readers
+            .keys()
+            .filter(|&&g| !self.recovery.corrupted.iter().any(|c| c.file == g))
+            .any(|g| worth_merging(&stats.get(g).cloned().unwrap_or_default(), &self.options))
+    }
+
+    /// Merges every sealed generation on the calling thread, however little
+    /// garbage it holds. Generations `open` found damaged are left alone.
+    ///
+    /// Waits for a running background merge first and runs even while
+    /// background compaction is paused.
+    pub fn compaction(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
         }
-        self.uncompacted = 0;
+        let compaction_gen = {
+            let mut guard = self.writer.lock().unwrap();
+            self.begin_compaction(guard.as_mut().ok_or(Error::ReadOnly)?)?
+        };
+        let compactor = self.compactor.as_ref().ok_or(Error::ReadOnly)?;
+        compactor.run_inline(compaction_gen)
+    }
 
+    /// Queues a merge on the background compactor and returns at once. Only
+    /// generations past `frag_threshold` or `dead_bytes_threshold` are
+    /// rewritten. Does nothing while a merge is queued or running, or when no
+    /// generation is worth rewriting.
+    pub fn trigger_compaction(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        match &self.compactor {
+            Some(compactor) if compactor.is_idle() => {}
+            _ => return Ok(()),
+        }
+        let compaction_gen = {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            // Sealing the active file for a merge with nothing to rewrite
+            // would only leave a small generation behind.
+            if !self.has_merge_candidates() {
+                self.uncompacted.store(0, Ordering::Relaxed);
+                return Ok(());
+            }
+            self.begin_compaction(active)?
+        };
+        if let Some(compactor) = &self.compactor {
+            compactor.schedule(compaction_gen);
+        }
         Ok(())
     }
+
+    /// Stops the background compactor from starting merges, whether triggered
+    /// explicitly or by `compaction_threshold`. A running merge is finished.
+    pub fn pause_compaction(&self) {
+        if let Some(compactor) = &self.compactor {
+            compactor.pause();
+        }
+    }
+
+    pub fn resume_compaction(&self) {
+        if let Some(compactor) = &self.compactor {
+            compactor.resume();
+        }
+    }
+
+    /// Blocks until the background compactor is idle, or only paused, and
+    /// returns the error of a failed background merge.
+    pub fn wait_for_compaction(&self) -> Result<()> {
+        match &self.compactor {
+            Some(compactor) => compactor.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Live and dead bytes of every generation.
+    pub fn file_stats(&self) -> BTreeMap<u64, FileStats> {
+        let stats = self.stats.lock().unwrap();
+        self.keydir
+            .load()
+            .readers
+            .keys()
+            .map(|g| (*g, stats.get(g).cloned().unwrap_or_default()))
+            .collect()
+    }
 }
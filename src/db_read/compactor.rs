@@ -0,0 +1,136 @@
+use crate::db_read::compaction::{MergeContext, merge};
+use crate::{Error, Options, Result};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Default)]
+struct State {
+    /// Generation reserved for a merge that has not started yet.
+    pending: Option<u64>,
+    running: bool,
+    paused: bool,
+    stop: bool,
+    /// Failure of the last background merge, handed out by `wait`.
+    error: Option<Error>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Background thread that merges fragmented generations while writes continue
+/// on the active file.
+///
+/// At most one merge is queued or running at a time. A running merge is
+/// finished when the compactor is dropped together with the store, a queued
+/// one is abandoned.
+#[derive(Debug)]
+pub(crate) struct Compactor {
+    shared: Arc<Shared>,
+    ctx: MergeContext,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub(crate) fn spawn(ctx: MergeContext, options: &Options) -> Self {
+        let shared = Arc::new(Shared::default());
+        let handle = {
+            let (shared, ctx, options) = (shared.clone(), ctx.clone(), options.clone());
+            thread::spawn(move || {
+                loop {
+                    let compaction_gen = {
+                        let mut state = shared.state.lock().unwrap();
+                        loop {
+                            if state.stop {
+                                return;
+                            }
+                            if !state.paused
+                                && !state.running
+                                && let Some(compaction_gen) = state.pending.take()
+                            {
+                                state.running = true;
+                                break compaction_gen;
+                            }
+                            state = shared.changed.wait(state).unwrap();
+                        }
+                    };
+                    let inputs = ctx.select(compaction_gen, &options);
+                    let res = merge(&ctx, &inputs, compaction_gen);
+                    let mut state = shared.state.lock().unwrap();
+                    state.running = false;
+                    if let Err(e) = res {
+                        state.error = Some(e);
+                    }
+                    shared.changed.notify_all();
+                }
+            })
+        };
+        Self {
+            shared,
+            ctx,
+            handle: Some(handle),
+        }
+    }
+
+    /// Whether no merge is queued or running.
+    pub(crate) fn is_idle(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        !state.running && state.pending.is_none()
+    }
+
+    /// Queues a merge of the fragmented generations below `compaction_gen`.
+    pub(crate) fn schedule(&self, compaction_gen: u64) {
+        self.shared.state.lock().unwrap().pending = Some(compaction_gen);
+        self.shared.changed.notify_all();
+    }
+
+    /// Holds queued merges back until `resume`. A running merge is finished.
+    pub(crate) fn pause(&self) {
+        self.shared.state.lock().unwrap().paused = true;
+    }
+
+    pub(crate) fn resume(&self) {
+        self.shared.state.lock().unwrap().paused = false;
+        self.shared.changed.notify_all();
+    }
+
+    /// Blocks until the running merge, and the queued one unless paused, is
+    /// done. Returns the error of a failed background merge.
+    pub(crate) fn wait(&self) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.running || (state.pending.is_some() && !state.paused) {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        state.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Merges every undamaged generation below `compaction_gen` on the calling
+    /// thread, after the running merge and in place of the queued one.
+    pub(crate) fn run_inline(&self, compaction_gen: u64) -> Result<()> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            while state.running {
+                state = self.shared.changed.wait(state).unwrap();
+            }
+            state.pending = None;
+            state.running = true;
+        }
+        let inputs = self.ctx.mergeable(compaction_gen);
+        let res = merge(&self.ctx, &inputs, compaction_gen);
+        self.shared.state.lock().unwrap().running = false;
+        self.shared.changed.notify_all();
+        res
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.changed.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::db_format;
+use crate::db_read::compaction;
+use crate::db_read::reader::{LoadedDir, apply, load_dir, sorted_file_list};
+use crate::{
+    BitCaskPlus, Changes, DataReader, Error, FileStats, Keys, Meta, Options, RecoveryReport,
+    Result, Scan, Values,
+};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+/// Store opened with [`BitCaskPlus::open_read_only`].
+///
+/// Never creates, truncates or deletes a file and has no write methods, so it
+/// works on read-only mounts and next to a writer in another process.
+#[derive(Debug, Clone)]
+pub struct ReadOnlyBitCaskPlus {
+    inner: BitCaskPlus,
+}
+
+impl BitCaskPlus {
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<ReadOnlyBitCaskPlus> {
+        ReadOnlyBitCaskPlus::open_with(path, Options::default())
+    }
+}
+
+impl ReadOnlyBitCaskPlus {
+    /// Opens with `options`, whatever their `read_only` says.
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        let inner = BitCaskPlus::open_with(path, options.read_only(true))?;
+        Ok(Self { inner })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_bytes(key)
+    }
+
+    pub fn iter(&self) -> Scan {
+        self.inner.iter()
+    }
+
+    pub fn keys(&self) -> Keys {
+        self.inner.keys()
+    }
+
+    pub fn values(&self) -> Values {
+        self.inner.values()
+    }
+
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Scan {
+        self.inner.range(range)
+    }
+
+    pub fn prefix(&self, prefix: impl AsRef<[u8]>) -> Scan {
+        self.inner.prefix(prefix)
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Meta)>> {
+        self.inner.get_with_meta(key)
+    }
+
+    pub fn changes_since(&self, seqno: u64) -> Result<Changes> {
+        self.inner.changes_since(seqno)
+    }
+
+    /// Corruption found by the last `open`. Nothing was repaired.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        self.inner.recovery_report()
+    }
+
+    pub fn file_stats(&self) -> BTreeMap<u64, FileStats> {
+        self.inner.file_stats()
+    }
+
+    /// Picks up what a writer appended since the last open or refresh.
+    ///
+    /// New records and generations are read incrementally. Once the writer
+    /// has merged or is merging generations, the keydir is rebuilt instead.
+    pub fn refresh(&self) -> Result<()> {
+        let store = &self.inner;
+        // Nothing is written here, the mutex only orders keydir updates.
+        let _guard = store.writer.lock().unwrap();
+        let file_list = sorted_file_list(&store.path)?;
+        let known = store.keydir.load_full();
+        let newest = known.readers.keys().max().copied();
+        let merged = !compaction::recover_merges(&store.path, true)?.is_empty()
+            || known.readers.keys().any(|g| !file_list.contains(g))
+            || file_list
+                .iter()
+                .any(|g| !known.readers.contains_key(g) && Some(*g) < newest);
+        if !merged {
+            match self.read_appended(file_list) {
+                // A merge deleted a new generation since the listing.
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                res => return res,
+            }
+        }
+        let LoadedDir {
+            keydir,
+            stats,
+            compacted_seqno,
+            ..
+        } = load_dir(&store.path, true)?;
+        store
+            .compacted_seqno
+            .store(compacted_seqno, Ordering::SeqCst);
+        store.keydir.store(Arc::new(keydir));
+        *store.stats.lock().unwrap() = stats;
+        Ok(())
+    }
+
+    /// Reads the records appended to `file_list` since the last open or refresh.
+    fn read_appended(&self, file_list: Vec<u64>) -> Result<()> {
+        let store = &self.inner;
+        let mut stats = store.stats.lock().unwrap();
+        crate::update_keydir(&store.keydir, |keydir| -> Result<()> {
+            for gen_num in file_list {
+                let mut reader = match keydir.readers.get(&gen_num) {
+                    Some(reader) => reader.clone(),
+                    None => {
+                        let file = File::open(store.path.join(format!("{}.db", gen_num)))?;
+                        // The writer has not finished writing the file header yet.
+                        if file.metadata()?.len() < db_format::FILE_HEADER_LEN {
+                            continue;
+                        }
+                        DataReader::new(file, gen_num)?
+                    }
+                };
+                // A record the writer is still appending is read on the next refresh.
+                for (cmd, cmd_pos) in reader.by_ref().map_while(Result::ok) {
+                    apply(&mut keydir.map, &mut stats, cmd, cmd_pos);
+                }
+                keydir.readers.insert(gen_num, reader);
+            }
+            Ok(())
+        })
+    }
+}
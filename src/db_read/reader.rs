@@ -1,35 +1,55 @@
-use crate::{BitCaskPlus, Command, CommandPos, DataReader, Result};
-use std::collections::HashMap;
+use crate::db_format;
+use crate::db_read::compaction::{self, MergeContext};
+use crate::db_read::compactor::Compactor;
+use crate::db_write::syncer::Syncer;
+use crate::db_write::writer::ActiveFile;
+use crate::{
+    BitCaskPlus, Command, CommandPos, CorruptRecord, DataReader, Error, FileStats, Keydir, Meta,
+    Options, RecoveryReport, Result, SyncPolicy,
+};
+use arc_swap::ArcSwap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, Write};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
 
 struct HintReader<R: io::Read> {
     reader: R,
+    /// Bytes of the hint file not read yet.
+    remaining: u64,
 }
 
 impl<R: io::Read> HintReader<R> {
-    fn new(reader: R) -> Self {
-        Self { reader }
+    fn new(reader: R, remaining: u64) -> Self {
+        Self { reader, remaining }
     }
 }
 
 impl<R: io::Read> Iterator for HintReader<R> {
-    type Item = io::Result<(String, CommandPos)>;
+    type Item = io::Result<(Vec<u8>, CommandPos, bool)>;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut header = [0u8; 4];
+        let mut header = [0u8; db_format::HINT_ENTRY_HEADER_LEN];
         match self.reader.read_exact(&mut header) {
             Ok(_) => {
-                let len = u32::from_le_bytes(header) as usize;
-                let mut buffer = vec![0u8; len];
-                if let Err(e) = self.reader.read_exact(&mut buffer) {
+                // The key length is not checked yet, so it must not size the read.
+                let entry_len = db_format::hint_entry_len(&header) as u64;
+                if entry_len > self.remaining {
+                    return Some(Err(io::Error::other("hint entry runs past the end")));
+                }
+                self.remaining -= entry_len;
+                let mut entry = header.to_vec();
+                entry.resize(entry_len as usize, 0);
+                if let Err(e) = self
+                    .reader
+                    .read_exact(&mut entry[db_format::HINT_ENTRY_HEADER_LEN..])
+                {
                     return Some(Err(e));
                 }
-                let res =
-                    serde_json::from_slice(&buffer).map_err(|e| io::Error::other(e.to_string()));
-
+                let res = db_format::decode_hint(&entry)
+                    .ok_or_else(|| io::Error::other("hint crc mismatch"));
                 Some(res)
             }
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
@@ -38,8 +58,40 @@ impl<R: io::Read> Iterator for HintReader<R> {
     }
 }
 
-pub fn sorted_file_list(path: &Path) -> io::Result<Vec<u64>> {
-    let mut file_list: Vec<u64> = fs::read_dir(&path)?
+const LOCK_FILE: &str = "LOCK";
+
+/// Single data file written by the first versions of the store.
+const LEGACY_FILE: &str = "bitcaskplus.db";
+
+/// Takes the advisory lock that keeps a second writer out of the directory.
+fn lock_dir(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Adopts a leftover `bitcaskplus.db` as generation 0, older than any other.
+///
+/// It is left alone if a generation 0 already exists.
+fn import_legacy_file(path: &Path) -> Result<()> {
+    let legacy_path = path.join(LEGACY_FILE);
+    let gen_path = path.join("0.db");
+    if !legacy_path.is_file() || gen_path.exists() {
+        return Ok(());
+    }
+    fs::rename(&legacy_path, &gen_path)?;
+    compaction::sync_dir(path)
+}
+
+pub fn sorted_file_list(path: &Path) -> Result<Vec<u64>> {
+    let mut file_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|r| -> Result<_> { Ok(r?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("db".as_ref()))
         .flat_map(|path| {
@@ -54,112 +106,359 @@ pub fn sorted_file_list(path: &Path) -> io::Result<Vec<u64>> {
     Ok(file_list)
 }
 
-pub fn load(
-    path: &PathBuf,
+/// Reads the hint file written next to a merged generation, with a flag
+/// telling tombstones apart.
+///
+/// Returns `None` when the hint is missing, unreadable, in the old JSON layout,
+/// fails its checksum or does not cover the whole data file, so the caller can
+/// fall back to a scan.
+fn load_hint(
+    path: &Path,
+    file_num: u64,
+    data_len: u64,
+) -> Option<Vec<(Vec<u8>, CommandPos, bool)>> {
+    let hint_path = path.join(format!("{}.db.hint", file_num));
+    let hint_file = File::open(&hint_path).ok()?;
+    let hint_len = hint_file.metadata().ok()?.len();
+    let mut f_reader = io::BufReader::new(hint_file);
+    let mut header = [0u8; db_format::FILE_HEADER_LEN as usize];
+    f_reader.read_exact(&mut header).ok()?;
+    if header != db_format::hint_header() {
+        return None;
+    }
+    let remaining = hint_len - db_format::FILE_HEADER_LEN;
+    let entries: io::Result<Vec<(Vec<u8>, CommandPos, bool)>> =
+        HintReader::new(f_reader, remaining).collect();
+    let mut entries = entries.ok()?;
+
+    // A merged file holds exactly the hinted records back to back.
+    let covered: u64 = entries.iter().map(|(_, pos, _)| pos.len).sum();
+    if db_format::FILE_HEADER_LEN + covered != data_len {
+        return None;
+    }
+    for (_, pos, _) in &mut entries {
+        if pos.pos + pos.len > data_len {
+            return None;
+        }
+        pos.file_num = file_num;
+    }
+    Some(entries)
+}
+
+/// Replays one record into the keydir and returns the bytes it made dead.
+pub(crate) fn apply(
+    map: &mut imbl::OrdMap<Vec<u8>, CommandPos>,
+    stats: &mut HashMap<u64, FileStats>,
+    cmd: Command,
+    cmd_pos: CommandPos,
+) -> u64 {
+    match cmd {
+        Command::Set { key, .. } => insert(map, stats, key, cmd_pos),
+        Command::Remove { key } => {
+            let old_pos = map.remove(&key);
+            crate::account(stats, &cmd_pos, true, old_pos.as_ref());
+            old_pos.map_or(0, |old_pos| old_pos.len) + cmd_pos.len
+        }
+    }
+}
+
+/// Indexes a value unless it has expired, in which case the key goes as if
+/// removed. Returns the bytes this made dead.
+fn insert(
+    map: &mut imbl::OrdMap<Vec<u8>, CommandPos>,
+    stats: &mut HashMap<u64, FileStats>,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+) -> u64 {
+    if !cmd_pos.is_live(db_format::timestamp()) {
+        let old_pos = map.remove(&key);
+        crate::account(stats, &cmd_pos, false, old_pos.as_ref());
+        crate::expire(stats, &cmd_pos);
+        return old_pos.map_or(0, |old_pos| old_pos.len) + cmd_pos.len;
+    }
+    let old_pos = map.insert(key, cmd_pos.clone());
+    crate::account(stats, &cmd_pos, false, old_pos.as_ref());
+    old_pos.map_or(0, |old_pos| old_pos.len)
+}
+
+/// Rebuilds the keydir entries of one generation.
+///
+/// Corruption found while scanning is recorded in `report`, and `last_seqno`
+/// is raised to the highest sequence number found. When
+/// `truncate_tail` is set a torn tail is cut off at the last good record, so
+/// later appends do not land behind it.
+pub(crate) fn load(
+    path: &Path,
     file_num: u64,
-    map: &mut HashMap<String, CommandPos>,
-) -> io::Result<(DataReader, u64)> {
-    let log_path = &path.join(format!("{}.db", file_num));
+    map: &mut imbl::OrdMap<Vec<u8>, CommandPos>,
+    stats: &mut HashMap<u64, FileStats>,
+    report: &mut RecoveryReport,
+    last_seqno: &mut u64,
+    truncate_tail: bool,
+) -> Result<(DataReader, u64)> {
+    let log_path = path.join(format!("{}.db", file_num));
     let file = OpenOptions::new()
         .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
+        .write(truncate_tail)
         .open(&log_path)?;
-    let mut reader = DataReader::new(file.try_clone().expect("clone failed"), 0);
+    let data_len = file.metadata()?.len();
+    let mut reader = DataReader::new(file.try_clone().expect("clone failed"), file_num)?;
+    let file_handle = file;
     let mut uncompacted = 0;
-    //TODO: Got mismatch error when introducing hint file.
-    /*let hint_path = &path.join(format!("{}.db.hint", file_num));
-    if hint_path.exists() {
-        /*let f_reader = io::BufReader::new(File::open(&hint_path)?);
-        let hint_map: io::Result<HashMap<String, CommandPos>> = HintReader::new(f_reader).collect();
-        map.extend(hint_map?);*/
-    }*/
-    for result in reader.by_ref() {
+    if let Some(entries) = load_hint(path, file_num, data_len) {
+        for (key, cmd_pos, tombstone) in entries {
+            *last_seqno = (*last_seqno).max(cmd_pos.seqno.unwrap_or(0));
+            uncompacted += if tombstone {
+                apply(map, stats, Command::Remove { key }, cmd_pos)
+            } else {
+                insert(map, stats, key, cmd_pos)
+            };
+        }
+        // Appends are read from the end of the file, the hint covered the rest.
+        reader.cursor = data_len;
+        return Ok((reader, uncompacted));
+    }
+    while let Some(result) = reader.next() {
         match result {
-            Ok((cmd, mut cmd_pos)) => {
-                cmd_pos.file_num = file_num;
-                match cmd {
-                    Command::Set { key, .. } => {
-                        if let Some(old_pos) = map.insert(key, cmd_pos) {
-                            uncompacted += old_pos.len;
-                        }
-                    }
-                    Command::Remove { key } => {
-                        if let Some(old_pos) = map.remove(&key) {
-                            uncompacted += old_pos.len;
-                        }
-                        uncompacted += cmd_pos.len;
-                    }
-                }
+            Ok((cmd, cmd_pos)) => {
+                *last_seqno = (*last_seqno).max(cmd_pos.seqno.unwrap_or(0));
+                uncompacted += apply(map, stats, cmd, cmd_pos);
             }
-            Err(e) => {
-                eprintln!("read data error：{}", e);
+            Err(Error::Corruption { file, offset }) => {
+                let record = CorruptRecord {
+                    file,
+                    offset,
+                    skipped_bytes: data_len - offset,
+                };
+                // Only a record cut short by the end of the file is a torn
+                // append, the records behind a damaged one are kept on disk.
+                if truncate_tail && reader.runs_past(offset, data_len)? {
+                    file_handle.set_len(offset)?;
+                    file_handle.sync_all()?;
+                    report.truncated = Some(record);
+                } else {
+                    report.corrupted.push(record);
+                }
                 break;
             }
+            Err(e) => return Err(e),
         }
     }
     Ok((reader, uncompacted))
 }
 
+/// Everything `open` rebuilds from the data files.
+pub(crate) struct LoadedDir {
+    pub keydir: Keydir,
+    pub stats: HashMap<u64, FileStats>,
+    pub uncompacted: u64,
+    pub recovery: RecoveryReport,
+    /// Highest sequence number handed out, 0 if there is none.
+    pub last_seqno: u64,
+    /// Highest sequence number of a remove a merge dropped, 0 if there is none.
+    pub compacted_seqno: u64,
+}
+
+/// Settles interrupted merges and loads every generation in `path`.
+///
+/// Unless `read_only` is set, the torn tail of the last generation is cut off.
+pub(crate) fn load_dir(path: &Path, read_only: bool) -> Result<LoadedDir> {
+    loop {
+        let skipped = compaction::recover_merges(path, read_only)?;
+        let listed = sorted_file_list(path)?;
+        let loaded = load_files(path, &listed, &skipped, read_only);
+        if !read_only {
+            return loaded;
+        }
+        // A writer merging while the files were read can delete some of them,
+        // or leave a mix of its inputs and its output. Both change the listing,
+        // and whatever came of the load is thrown away.
+        if compaction::recover_merges(path, true)? == skipped && sorted_file_list(path)? == listed {
+            return loaded;
+        }
+    }
+}
+
+fn load_files(
+    path: &Path,
+    listed: &[u64],
+    skipped: &HashSet<u64>,
+    read_only: bool,
+) -> Result<LoadedDir> {
+    let file_list: Vec<u64> = listed
+        .iter()
+        .copied()
+        .filter(|f| !skipped.contains(f))
+        .collect();
+    let mut keydir = Keydir::default();
+    let mut stats = HashMap::new();
+    let mut uncompacted = 0;
+    let mut recovery = RecoveryReport::default();
+    let mut last_seqno = 0;
+
+    for &f in &file_list {
+        let truncate_tail = !read_only && Some(&f) == file_list.last();
+        let (reader, un_com) = load(
+            path,
+            f,
+            &mut keydir.map,
+            &mut stats,
+            &mut recovery,
+            &mut last_seqno,
+            truncate_tail,
+        )?;
+        uncompacted += un_com;
+        keydir.readers.insert(f, reader);
+    }
+    let mark = compaction::read_seqno_mark(path)?;
+    Ok(LoadedDir {
+        keydir,
+        stats,
+        uncompacted,
+        recovery,
+        last_seqno: last_seqno.max(mark.last),
+        compacted_seqno: mark.compacted,
+    })
+}
+
+/// Reads the value of the record at `p` from the files of `keydir`.
+pub(crate) fn read_value(keydir: &Keydir, p: &CommandPos) -> Result<Option<Vec<u8>>> {
+    let reader = keydir
+        .readers
+        .get(&p.file_num)
+        .ok_or(Error::FileNotFound(p.file_num))?;
+    let cmd = reader.read_command(p.pos, p.len)?;
+    if let Command::Set { value, .. } = cmd {
+        Ok(Some(value))
+    } else {
+        Ok(None)
+    }
+}
+
 impl BitCaskPlus {
     pub fn get(&self, key: &str) -> Result<Option<String>> {
-        let pos_info = {
-            let map = self.map.read().unwrap();
-            map.get(key).cloned()
-        };
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
-        let p = match pos_info {
-            Some(p) => p,
-            None => return Ok(None),
-        };
-        let readers = self.readers.read().unwrap();
-        let reader = readers.get(&p.file_num).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Log file {} not found", p.file_num),
-            )
-        })?;
-        let (expect_crc, buffer) = { reader.read_data(p.pos, p.len)? };
-
-        let actual_crc = crc32fast::hash(&buffer[12..]);
-        if actual_crc != expect_crc {
-            return Err(io::Error::other("crc mismatch").into());
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // One version of the keydir, compaction swaps positions and readers together.
+        let keydir = self.keydir.load();
+        match keydir.map.get(key) {
+            Some(p) if p.is_live(db_format::timestamp()) => read_value(&keydir, p),
+            _ => Ok(None),
         }
-        let cmd = serde_json::from_slice(&buffer[12..])
-            .map_err(|e| format!("Serde json deserialization error: {}", e))?;
-        if let Command::Set { value, .. } = cmd {
-            Ok(Some(value))
-        } else {
-            Ok(None)
+    }
+
+    /// Reads the value of `key` together with where and when it was written.
+    pub fn get_with_meta(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Meta)>> {
+        let keydir = self.keydir.load();
+        let p = match keydir.map.get(key) {
+            Some(p) if p.is_live(db_format::timestamp()) => p,
+            _ => return Ok(None),
+        };
+        let reader = keydir
+            .readers
+            .get(&p.file_num)
+            .ok_or(Error::FileNotFound(p.file_num))?;
+        match reader.read_record(p.pos, p.len)? {
+            (Command::Set { value, .. }, meta) => Ok(Some((
+                value,
+                Meta {
+                    timestamp: meta.timestamp,
+                    seqno: meta.seqno,
+                    file: p.file_num,
+                    offset: p.pos,
+                },
+            ))),
+            (Command::Remove { .. }, _) => Ok(None),
         }
     }
 
-    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+    /// Corruption found and repaired by the last `open`.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    /// Same as [`BitCaskPlus::open`].
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open(path)
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, Options::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        options.validate()?;
         let path: PathBuf = path.into();
-        fs::create_dir_all(&path)?;
-        let file_list = sorted_file_list(&path)?;
-        let mut readers = HashMap::new();
-        let mut map: HashMap<String, CommandPos> = HashMap::new();
-        let mut uncompacted = 0;
-
-        for &f in &file_list {
-            let (reader, un_com) = load(&path, f, &mut map)?;
-            uncompacted += un_com;
-            readers.insert(f, reader);
+        if options.create_dir && !options.read_only {
+            fs::create_dir_all(&path)?;
         }
+        // Readers never write, so they do not need to keep a writer out.
+        let lock = if options.read_only {
+            None
+        } else {
+            Some(Arc::new(lock_dir(&path)?))
+        };
+        options.reconcile(&path)?;
+        // Read-only opens see it once a writer has imported it.
+        if !options.read_only {
+            import_legacy_file(&path)?;
+        }
+        let LoadedDir {
+            keydir,
+            stats,
+            uncompacted,
+            recovery,
+            last_seqno,
+            compacted_seqno,
+        } = load_dir(&path, options.read_only)?;
 
-        let cur_gen = file_list.last().unwrap_or(&0) + 1;
-        let file = crate::new_log_file(&path, cur_gen, &mut readers)?;
-        let writer = io::BufWriter::new(file);
+        let writer = if options.read_only {
+            None
+        } else {
+            let last_gen = keydir.readers.keys().max().copied();
+            let last = last_gen.and_then(|g| keydir.readers.get(&g));
+            match last {
+                // Appends behind a damaged record would never be loaded again.
+                Some(reader) if recovery.corrupted.iter().any(|c| c.file == reader.file_num) => {
+                    Some(ActiveFile::pending(reader.file_num + 1, last_seqno + 1))
+                }
+                _ => Some(ActiveFile::resume(&path, last, last_seqno + 1)?),
+            }
+        };
+        let context = MergeContext {
+            path: path.clone(),
+            keydir: Arc::new(ArcSwap::from_pointee(keydir)),
+            writer: Arc::new(Mutex::new(writer)),
+            stats: Arc::new(Mutex::new(stats)),
+            compacted_seqno: Arc::new(AtomicU64::new(compacted_seqno)),
+            damaged: recovery.corrupted.iter().map(|c| c.file).collect(),
+        };
+        let compactor =
+            (!options.read_only).then(|| Arc::new(Compactor::spawn(context.clone(), &options)));
+        let syncer = match options.sync_policy {
+            SyncPolicy::SyncEvery(interval) if !options.read_only => {
+                Some(Arc::new(Syncer::spawn(context.writer.clone(), interval)))
+            }
+            _ => None,
+        };
         let res = {
             Self {
                 path,
-                map: Arc::new(RwLock::new(map)),
-                writer: Arc::new(Mutex::new(writer)),
-                readers: Arc::new(RwLock::new(readers)),
-                uncompacted,
-                cur_gen,
+                keydir: context.keydir,
+                writer: context.writer,
+                stats: context.stats,
+                compacted_seqno: context.compacted_seqno,
+                uncompacted: Arc::new(AtomicU64::new(uncompacted)),
+                recovery: Arc::new(recovery),
+                options,
+                compactor,
+                _syncer: syncer,
+                _lock: lock,
             }
         };
 
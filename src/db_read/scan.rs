@@ -0,0 +1,187 @@
+use crate::db_format;
+use crate::db_read::reader::read_value;
+use crate::{BitCaskPlus, CommandPos, Error, Keydir, Result};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// Live entries of a key range in key order, returned by
+/// [`BitCaskPlus::iter`], [`BitCaskPlus::range`] and [`BitCaskPlus::prefix`].
+///
+/// Iterates the keydir as it was when the scan started and reads each value
+/// only when its entry is reached. Walks backwards through `rev`.
+#[derive(Debug, Clone)]
+pub struct Scan {
+    keydir: Arc<Keydir>,
+    /// Keys left to scan, narrowed from both ends as entries are returned.
+    front: Bound<Vec<u8>>,
+    back: Bound<Vec<u8>>,
+    /// Start of the scan, values that expired before it are skipped.
+    now: u64,
+}
+
+impl Scan {
+    fn new(keydir: Arc<Keydir>, front: Bound<Vec<u8>>, back: Bound<Vec<u8>>) -> Self {
+        Self {
+            keydir,
+            front,
+            back,
+            now: db_format::timestamp(),
+        }
+    }
+
+    /// Whether the two ends have met, or never overlapped to begin with.
+    fn is_empty(&self) -> bool {
+        match (&self.front, &self.back) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    /// Reads the value a keydir entry points at.
+    fn read(&self, key: Vec<u8>, p: &CommandPos) -> Result<(Vec<u8>, Vec<u8>)> {
+        // The keydir only points at values, never at tombstones.
+        let value = read_value(&self.keydir, p)?.ok_or(Error::Corruption {
+            file: p.file_num,
+            offset: p.pos,
+        })?;
+        Ok((key, value))
+    }
+
+    /// Takes the first or last live entry left and narrows that end past it.
+    fn step(&mut self, front: bool) -> Option<(Vec<u8>, CommandPos)> {
+        loop {
+            if self.is_empty() {
+                return None;
+            }
+            let bounds = (
+                self.front.as_ref().map(Vec::as_slice),
+                self.back.as_ref().map(Vec::as_slice),
+            );
+            let mut range = self.keydir.map.range::<_, [u8]>(bounds);
+            let (key, p) = if front {
+                range.next()?
+            } else {
+                range.next_back()?
+            };
+            let (key, p) = (key.clone(), p.clone());
+            let end = if front {
+                &mut self.front
+            } else {
+                &mut self.back
+            };
+            *end = Bound::Excluded(key.clone());
+            if p.is_live(self.now) {
+                return Some((key, p));
+            }
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, p) = self.step(true)?;
+        Some(self.read(key, &p))
+    }
+}
+
+impl DoubleEndedIterator for Scan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, p) = self.step(false)?;
+        Some(self.read(key, &p))
+    }
+}
+
+/// Live keys in key order, returned by [`BitCaskPlus::keys`]. Reads no file.
+#[derive(Debug, Clone)]
+pub struct Keys {
+    scan: Scan,
+}
+
+impl Iterator for Keys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.step(true).map(|(key, _)| key)
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.scan.step(false).map(|(key, _)| key)
+    }
+}
+
+/// Live values in key order, returned by [`BitCaskPlus::values`].
+#[derive(Debug, Clone)]
+pub struct Values {
+    scan: Scan,
+}
+
+impl Iterator for Values {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.scan.next().map(|entry| entry.map(|(_, value)| value))
+    }
+}
+
+impl DoubleEndedIterator for Values {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.scan
+            .next_back()
+            .map(|entry| entry.map(|(_, value)| value))
+    }
+}
+
+/// First key past every key starting with `prefix`, if there is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl BitCaskPlus {
+    /// Scans every live entry, for exports or consistency checks.
+    pub fn iter(&self) -> Scan {
+        Scan::new(self.keydir.load_full(), Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub fn keys(&self) -> Keys {
+        Keys { scan: self.iter() }
+    }
+
+    pub fn values(&self) -> Values {
+        Values { scan: self.iter() }
+    }
+
+    /// Scans the live keys in `range`, such as `b"a".as_slice()..b"b".as_slice()`.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Scan {
+        let to_vec = |bound: Bound<&K>| bound.map(|k| k.as_ref().to_vec());
+        Scan::new(
+            self.keydir.load_full(),
+            to_vec(range.start_bound()),
+            to_vec(range.end_bound()),
+        )
+    }
+
+    /// Scans the live keys starting with `prefix`.
+    pub fn prefix(&self, prefix: impl AsRef<[u8]>) -> Scan {
+        let prefix = prefix.as_ref();
+        let end = prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        Scan::new(
+            self.keydir.load_full(),
+            Bound::Included(prefix.to_vec()),
+            end,
+        )
+    }
+}
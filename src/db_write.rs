@@ -1 +1,4 @@
 pub mod writer;
+pub mod syncer;
+pub mod batch;
+pub mod transaction;
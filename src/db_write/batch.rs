@@ -0,0 +1,117 @@
+use crate::db_format;
+use crate::db_read::reader::apply;
+use crate::{BitCaskPlus, Command, CommandPos, Error, Keydir, Result};
+use std::sync::atomic::Ordering;
+
+/// Puts and deletes that [`BitCaskPlus::write_batch`] applies all together.
+///
+/// The batch is appended as one checksummed record, so after a crash either
+/// all of it is loaded or none of it.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    cmds: Vec<Command>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.cmds.push(Command::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Deletes `key`, which does not have to exist.
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.cmds.push(Command::Remove { key: key.into() });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+}
+
+impl BitCaskPlus {
+    /// Applies every operation of `batch` in order, atomically.
+    ///
+    /// Readers see either none or all of the batch.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.commit_batch(batch, |_| Ok(()))
+    }
+
+    /// Writes `batch` if `check` accepts the keydir it is applied to.
+    pub(crate) fn commit_batch(
+        &self,
+        batch: WriteBatch,
+        check: impl FnOnce(&Keydir) -> Result<()>,
+    ) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for cmd in &batch.cmds {
+            match cmd {
+                Command::Set { key, value, .. } => self.check_sizes(key, value)?,
+                Command::Remove { key } => self.check_sizes(key, &[])?,
+            }
+        }
+        let timestamp = db_format::timestamp();
+
+        {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            check(&self.keydir.load())?;
+            // Records of a batch take consecutive sequence numbers.
+            let first_seqno = active.next_seqno;
+            let records: Vec<Vec<u8>> = (first_seqno..)
+                .zip(&batch.cmds)
+                .map(|(seqno, cmd)| db_format::encode(cmd, timestamp, Some(seqno)))
+                .collect();
+            // The frame stores the length of the batch like a value length.
+            let len: u64 = records.iter().map(|record| record.len() as u64).sum();
+            if len > u32::MAX as u64 {
+                return Err(Error::ValueTooLarge {
+                    len,
+                    max: u32::MAX as u64,
+                });
+            }
+            active.next_seqno += records.len() as u64;
+            let frame_pos =
+                self.append_record(active, &db_format::encode_batch(&records, timestamp))?;
+            let mut pos = frame_pos.pos + db_format::RECORD_HEADER_LEN;
+            let mut stats = self.stats.lock().unwrap();
+            let uncompacted = crate::update_keydir(&self.keydir, |keydir| {
+                let mut uncompacted = 0;
+                for ((cmd, record), seqno) in
+                    batch.cmds.into_iter().zip(&records).zip(first_seqno..)
+                {
+                    let cmd_pos = CommandPos {
+                        file_num: frame_pos.file_num,
+                        pos,
+                        len: record.len() as u64,
+                        expires_at: cmd.expires_at(),
+                        seqno: Some(seqno),
+                    };
+                    pos += cmd_pos.len;
+                    uncompacted += apply(&mut keydir.map, &mut stats, cmd, cmd_pos);
+                }
+                uncompacted
+            });
+            self.uncompacted.fetch_add(uncompacted, Ordering::Relaxed);
+        }
+
+        self.maybe_compact()
+    }
+}
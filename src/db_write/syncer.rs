@@ -0,0 +1,52 @@
+use crate::db_write::writer::ActiveFile;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Background thread behind [`SyncPolicy::SyncEvery`](crate::SyncPolicy::SyncEvery).
+///
+/// The active file is synced on every tick that follows a write, and once more
+/// when the syncer is dropped together with the store. A failed sync is
+/// returned by the next write or `sync`.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub(crate) fn spawn(writer: Arc<Mutex<Option<ActiveFile>>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            loop {
+                let done = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                if let Some(active) = writer.lock().unwrap().as_mut()
+                    && active.unsynced > 0
+                    && let Err(e) = active.sync()
+                {
+                    active.sync_error = Some(e);
+                }
+                if done {
+                    break;
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
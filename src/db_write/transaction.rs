@@ -0,0 +1,94 @@
+use crate::db_format;
+use crate::db_read::reader::read_value;
+use crate::{BitCaskPlus, CommandPos, Error, Keydir, Result, WriteBatch};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Reads and writes of one [`BitCaskPlus::transaction`].
+///
+/// Reads see the store as it was when the transaction started, plus its own
+/// writes. Writes are buffered and committed as one [`WriteBatch`].
+#[derive(Debug)]
+pub struct Transaction {
+    snapshot: Arc<Keydir>,
+    /// Position each key read had in the snapshot, `None` if it was absent.
+    reads: HashMap<Vec<u8>, Option<CommandPos>>,
+    /// Latest write of each key, `None` for a delete.
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl Transaction {
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let p = self.snapshot.map.get(key);
+        self.reads.insert(key.to_vec(), p.cloned());
+        match p {
+            Some(p) if p.is_live(db_format::timestamp()) => read_value(&self.snapshot, p),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: String, val: String) {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.batch.put(key.clone(), val.clone());
+        self.writes.insert(key, Some(val));
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Fails with [`Error::KeyNotFound`] if the transaction does not see `key`.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(Error::KeyNotFound);
+        }
+        self.batch.delete(key);
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
+
+impl BitCaskPlus {
+    /// Runs `f` in an optimistic transaction and commits its writes if it
+    /// returns `Ok`.
+    ///
+    /// The commit fails with [`Error::Conflict`], and writes nothing, if a key
+    /// `f` read has been written or removed since the transaction started, or
+    /// moved by a merge. `f` can then be run again.
+    pub fn transaction<T>(&self, f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+        let mut tx = Transaction {
+            snapshot: self.keydir.load_full(),
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+            batch: WriteBatch::new(),
+        };
+        let res = f(&mut tx)?;
+        let Transaction { reads, batch, .. } = tx;
+        // The keydir position of a key changes with every write of it.
+        self.commit_batch(batch, |keydir| {
+            if reads
+                .iter()
+                .all(|(key, p)| keydir.map.get(key) == p.as_ref())
+            {
+                Ok(())
+            } else {
+                Err(Error::Conflict)
+            }
+        })?;
+        Ok(res)
+    }
+}
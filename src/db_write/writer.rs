@@ -1,63 +1,404 @@
-use crate::{BitCaskPlus, COMPACTION_THRESHOLD, Command, CommandPos, Result};
-use std::io::{self, Seek, Write};
+use crate::db_format;
+use crate::db_read::compaction;
+use crate::{BitCaskPlus, Command, CommandPos, DataReader, Error, Result, SyncPolicy};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Append side of the store: the active generation and its handle.
+///
+/// Lives behind the writer mutex, so writers serialize on it and nothing else.
+#[derive(Debug)]
+pub(crate) struct ActiveFile {
+    /// `None` until the first record of a new generation creates its file.
+    pub(crate) writer: Option<BufWriter<File>>,
+    pub(crate) gen_num: u64,
+    /// Bytes appended since the last sync.
+    pub(crate) unsynced: u64,
+    /// Sequence number of the next record.
+    pub(crate) next_seqno: u64,
+    /// Failure of a background sync, returned by the next write or `sync`.
+    pub(crate) sync_error: Option<Error>,
+}
+
+impl ActiveFile {
+    pub(crate) fn new(file: File, gen_num: u64, next_seqno: u64) -> Self {
+        Self {
+            writer: Some(BufWriter::new(file)),
+            gen_num,
+            unsynced: 0,
+            next_seqno,
+            sync_error: None,
+        }
+    }
+
+    /// Generation `gen_num`, whose file is created on the first write.
+    pub(crate) fn pending(gen_num: u64, next_seqno: u64) -> Self {
+        Self {
+            writer: None,
+            gen_num,
+            unsynced: 0,
+            next_seqno,
+            sync_error: None,
+        }
+    }
+
+    /// Keeps appending to the last generation after `open` has validated it.
+    ///
+    /// Files in an older format, and merge outputs whose hint would go
+    /// stale, are left alone in favour of a new generation.
+    pub(crate) fn resume(path: &Path, last: Option<&DataReader>, next_seqno: u64) -> Result<Self> {
+        let reader = match last {
+            Some(reader) => reader,
+            None => return Ok(Self::pending(1, next_seqno)),
+        };
+        let hint_path = path.join(format!("{}.db.hint", reader.file_num));
+        if reader.version != db_format::FORMAT_VERSION || hint_path.exists() {
+            return Ok(Self::pending(reader.file_num + 1, next_seqno));
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path.join(format!("{}.db", reader.file_num)))?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self::new(file, reader.file_num, next_seqno))
+    }
+
+    /// Hands out the sequence number of the next record.
+    pub(crate) fn take_seqno(&mut self) -> u64 {
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        seqno
+    }
+
+    /// Flushes and syncs the file to disk.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Returns the failure of a background sync, once. The records it should
+    /// have synced may be lost even if a later sync succeeds.
+    pub(crate) fn take_sync_error(&mut self) -> Result<()> {
+        self.sync_error.take().map_or(Ok(()), Err)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
 
 impl BitCaskPlus {
-    pub fn write_data(&mut self, cmd: &Command) -> io::Result<CommandPos> {
-        let mut w = self.writer.lock().unwrap();
-        let pos = w.stream_position()?;
-        let json_data = serde_json::to_string(&cmd).map_err(|e| io::Error::other(e.to_string()))?;
-        let json_data_len = json_data.len() as u64;
-        let checksum = crc32fast::hash(json_data.as_bytes());
-        // CRC(4) + Len(8) + Data(N)
-        w.write_all(&checksum.to_le_bytes())?;
-        w.write_all(&json_data_len.to_le_bytes())?; // Little-Endian
-        w.write_all(json_data.as_bytes())?;
+    pub fn write_data(&self, cmd: &Command) -> Result<CommandPos> {
+        let mut guard = self.writer.lock().unwrap();
+        let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+        self.append(active, cmd)
+    }
+
+    fn append(&self, active: &mut ActiveFile, cmd: &Command) -> Result<CommandPos> {
+        let seqno = active.take_seqno();
+        let record = db_format::encode(cmd, db_format::timestamp(), Some(seqno));
+        let mut cmd_pos = self.append_record(active, &record)?;
+        cmd_pos.expires_at = cmd.expires_at();
+        cmd_pos.seqno = Some(seqno);
+        Ok(cmd_pos)
+    }
+
+    /// Appends one encoded record to the active file, rolling it over when full.
+    pub(crate) fn append_record(
+        &self,
+        active: &mut ActiveFile,
+        record: &[u8],
+    ) -> Result<CommandPos> {
+        active.take_sync_error()?;
+        let mut pos = self.active_writer(active)?.stream_position()?;
+        // Roll over to a new generation, unless the record would be alone in it anyway.
+        if pos > db_format::FILE_HEADER_LEN
+            && pos + record.len() as u64 > self.options.max_file_size
+        {
+            self.rotate(active, active.gen_num + 1)?;
+            pos = self.active_writer(active)?.stream_position()?;
+        }
+        let w = self.active_writer(active)?;
+        w.write_all(record)?;
         w.flush()?;
+        active.unsynced += record.len() as u64;
+        match self.options.sync_policy {
+            SyncPolicy::SyncEveryWrite => active.sync()?,
+            SyncPolicy::SyncEveryNBytes(n) if active.unsynced >= n => active.sync()?,
+            _ => {}
+        }
         Ok(CommandPos {
-            file_num: self.cur_gen,
+            file_num: active.gen_num,
             pos,
-            len: 12 + json_data_len,
+            len: record.len() as u64,
+            expires_at: None,
+            seqno: None,
         })
     }
 
-    pub fn set(&mut self, key: String, val: String) -> Result<()> {
-        let cmd = Command::Set {
-            key: key.clone(),
-            value: val,
-        };
+    /// Append handle of the active generation, creating its file if needed.
+    fn active_writer<'a>(&self, active: &'a mut ActiveFile) -> Result<&'a mut BufWriter<File>> {
+        if active.writer.is_none() {
+            let new_file = crate::update_keydir(&self.keydir, |keydir| {
+                crate::new_log_file(&self.path, active.gen_num, &mut keydir.readers)
+            })?;
+            // Syncing the records is no use if the file itself can vanish.
+            if self.options.sync_policy != SyncPolicy::OsManaged {
+                compaction::sync_dir(&self.path)?;
+            }
+            active.writer = Some(BufWriter::new(new_file));
+        }
+        Ok(active.writer.as_mut().expect("writer was just created"))
+    }
 
-        let cmd_pos = self.write_data(&cmd)?;
+    /// Seals the active file and continues in generation `gen_num`, which is
+    /// created on its first write.
+    pub(crate) fn rotate(&self, active: &mut ActiveFile, gen_num: u64) -> Result<()> {
+        if self.options.sync_policy == SyncPolicy::OsManaged {
+            active.flush()?;
+        } else {
+            active.sync()?;
+        }
+        active.writer = None;
+        active.gen_num = gen_num;
+        active.unsynced = 0;
+        Ok(())
+    }
+
+    /// Pushes buffered records to the operating system.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(active) = self.writer.lock().unwrap().as_mut() {
+            active.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Forces every appended record to disk, whatever the sync policy.
+    pub fn sync(&self) -> Result<()> {
+        let mut guard = self.writer.lock().unwrap();
+        let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+        active.take_sync_error()?;
+        active.sync()
+    }
+
+    pub(crate) fn check_sizes(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        if key.len() as u64 > self.options.max_key_size {
+            return Err(Error::KeyTooLarge {
+                len: key.len() as u64,
+                max: self.options.max_key_size,
+            });
+        }
+        if val.len() as u64 > self.options.max_value_size {
+            return Err(Error::ValueTooLarge {
+                len: val.len() as u64,
+                max: self.options.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn set(&self, key: String, val: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    pub fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.write_value(key, val, None)
+    }
+
+    /// Stores a value that reads as absent once `ttl` has passed.
+    pub fn set_with_ttl(&self, key: String, val: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), val.into_bytes(), ttl)
+    }
+
+    pub fn set_bytes_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = db_format::timestamp().saturating_add(ttl.as_millis() as u64);
+        self.write_value(key, val, Some(expires_at))
+    }
+
+    fn write_value(&self, key: Vec<u8>, val: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.check_sizes(&key, &val)?;
+        {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            self.put(active, key, val, expires_at)?;
+        }
+        self.maybe_compact()
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
         {
-            let mut m = self.map.write().unwrap();
-            if let Some(old_pos) = m.insert(key, cmd_pos) {
-                self.uncompacted += old_pos.len;
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            match self.keydir.load().map.get(key) {
+                Some(p) if p.is_live(db_format::timestamp()) => {}
+                _ => return Err(Error::KeyNotFound),
             }
+            self.delete(active, key)?;
         }
+        self.maybe_compact()
+    }
+
+    /// Writes a tombstone for every expired key and returns how many there
+    /// were.
+    ///
+    /// Expired keys read as absent anyway, and merges drop them. Purging
+    /// frees their keydir entries without waiting for a merge.
+    pub fn purge_expired(&self) -> Result<usize> {
+        let purged = {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            let now = db_format::timestamp();
+            let keydir = self.keydir.load_full();
+            let expired: Vec<&Vec<u8>> = keydir
+                .map
+                .iter()
+                .filter(|(_, p)| !p.is_live(now))
+                .map(|(key, _)| key)
+                .collect();
+            for key in &expired {
+                self.delete(active, key)?;
+            }
+            expired.len()
+        };
+        self.maybe_compact()?;
+        Ok(purged)
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compaction()?;
+    /// Replaces the value of `key` with `new` if it currently is `expected`,
+    /// `None` standing for an absent key on either side.
+    ///
+    /// Returns whether the swap happened. No other write can come between
+    /// the check and the write.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if let Some(new) = &new {
+            self.check_sizes(key, new)?;
         }
+        {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            let current = self.get_bytes(key)?;
+            if current.as_deref() != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(new) => self.put(active, key.to_vec(), new, None)?,
+                None if current.is_some() => self.delete(active, key)?,
+                None => {}
+            }
+        }
+        self.maybe_compact()?;
+        Ok(true)
+    }
 
-        Ok(())
+    /// Stores `val` unless `key` already exists, and returns whether it did.
+    pub fn set_if_absent(&self, key: Vec<u8>, val: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(&key, None, Some(val))
     }
 
-    pub fn remove(&mut self, key: &str) -> Result<()> {
-        let cmd = Command::Remove {
-            key: key.to_string(),
+    /// Replaces the value of `key` with what `f` makes of it, `None` meaning
+    /// absent, and returns the new value.
+    ///
+    /// `f` runs while holding the writer, so it should be quick.
+    pub fn update(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let new = {
+            let mut guard = self.writer.lock().unwrap();
+            let active = guard.as_mut().ok_or(Error::ReadOnly)?;
+            let current = self.get_bytes(key)?;
+            let new = f(current.as_deref());
+            match &new {
+                Some(new) => {
+                    self.check_sizes(key, new)?;
+                    self.put(active, key.to_vec(), new.clone(), None)?;
+                }
+                None if current.is_some() => self.delete(active, key)?,
+                None => {}
+            }
+            new
         };
+        self.maybe_compact()?;
+        Ok(new)
+    }
 
-        let cmd_pos = self.write_data(&cmd)?;
-        let old_pos = {
-            let mut m = self.map.write().unwrap();
-            m.remove(key)
-                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Key not found"))?
+    // The keydir is updated before the writer is released, so it follows the
+    // order of the log.
+    fn put(
+        &self,
+        active: &mut ActiveFile,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let cmd = Command::Set {
+            key: key.clone(),
+            value: val,
+            expires_at,
         };
-        self.uncompacted += old_pos.len + cmd_pos.len;
+        let cmd_pos = self.append(active, &cmd)?;
+        let old_pos = crate::update_keydir(&self.keydir, |keydir| {
+            keydir.map.insert(key, cmd_pos.clone())
+        });
+        crate::account(
+            &mut self.stats.lock().unwrap(),
+            &cmd_pos,
+            false,
+            old_pos.as_ref(),
+        );
+        if let Some(old_pos) = old_pos {
+            self.uncompacted.fetch_add(old_pos.len, Ordering::Relaxed);
+        }
+        Ok(())
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compaction()?;
+    fn delete(&self, active: &mut ActiveFile, key: &[u8]) -> Result<()> {
+        if !self.keydir.load().map.contains_key(key) {
+            return Err(Error::KeyNotFound);
         }
+        let cmd = Command::Remove { key: key.to_vec() };
+        let cmd_pos = self.append(active, &cmd)?;
+        let old_pos = crate::update_keydir(&self.keydir, |keydir| keydir.map.remove(key))
+            .ok_or(Error::KeyNotFound)?;
+        crate::account(
+            &mut self.stats.lock().unwrap(),
+            &cmd_pos,
+            true,
+            Some(&old_pos),
+        );
+        self.uncompacted
+            .fetch_add(old_pos.len + cmd_pos.len, Ordering::Relaxed);
+        Ok(())
+    }
 
+    /// Queues a merge once enough garbage has piled up. Must be called after
+    /// the writer is released.
+    pub(crate) fn maybe_compact(&self) -> Result<()> {
+        if self.uncompacted.load(Ordering::Relaxed) > self.options.compaction_threshold {
+            self.trigger_compaction()?;
+        }
         Ok(())
     }
 }
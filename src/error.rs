@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Errors returned by [`BitCaskPlus`](crate::BitCaskPlus).
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// `remove` was called for a key that is not stored.
+    KeyNotFound,
+    /// A record failed its checksum or could not be decoded.
+    Corruption { file: u64, offset: u64 },
+    /// A key is longer than [`Options::max_key_size`](crate::Options::max_key_size).
+    KeyTooLarge { len: u64, max: u64 },
+    /// A value is longer than [`Options::max_value_size`](crate::Options::max_value_size).
+    ValueTooLarge { len: u64, max: u64 },
+    /// The keydir points at a generation that has no data file.
+    FileNotFound(u64),
+    /// A data file was written by a newer format version.
+    UnsupportedVersion(u32),
+    Serialization(serde_json::Error),
+    InvalidOptions(String),
+    /// The options conflict with the ones the store was created with.
+    OptionsMismatch {
+        name: &'static str,
+        stored: u64,
+        requested: u64,
+    },
+    /// A write was attempted on a store opened read-only.
+    ReadOnly,
+    /// Another handle, possibly in another process, has the store open for writing.
+    Locked,
+    /// A value read through the `String` API is not valid UTF-8.
+    Utf8(FromUtf8Error),
+    /// A key a transaction read was written before it committed.
+    Conflict,
+    /// A merge dropped a remove numbered above the checkpoint passed to
+    /// [`changes_since`](crate::BitCaskPlus::changes_since). Checkpoints from
+    /// `compacted` on are still complete.
+    HistoryCompacted { since: u64, compacted: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::KeyNotFound => write!(f, "Key not found"),
+            Error::Corruption { file, offset } => {
+                write!(f, "corrupted record in log file {} at offset {}", file, offset)
+            }
+            Error::KeyTooLarge { len, max } => {
+                write!(f, "key of {} bytes exceeds the limit of {} bytes", len, max)
+            }
+            Error::ValueTooLarge { len, max } => {
+                write!(f, "value of {} bytes exceeds the limit of {} bytes", len, max)
+            }
+            Error::FileNotFound(file_num) => write!(f, "Log file {} not found", file_num),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::InvalidOptions(msg) => write!(f, "invalid options: {}", msg),
+            Error::OptionsMismatch {
+                name,
+                stored,
+                requested,
+            } => write!(
+                f,
+                "{} of {} is below the {} the store was created with",
+                name, requested, stored
+            ),
+            Error::ReadOnly => write!(f, "store is opened read-only"),
+            Error::Locked => write!(f, "store is locked by another writer"),
+            Error::Utf8(e) => write!(f, "value is not valid UTF-8: {}", e),
+            Error::Conflict => write!(f, "transaction conflicts with a concurrent write"),
+            Error::HistoryCompacted { since, compacted } => write!(
+                f,
+                "changes since {} were compacted, the oldest complete checkpoint is {}",
+                since, compacted
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::Utf8(e)
+    }
+}
@@ -1,141 +1,345 @@
+use arc_swap::ArcSwap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{self, BufWriter, Seek};
+use std::io::{self, Seek, Write};
 use std::os::unix::fs::FileExt;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
 
+pub mod db_format;
 pub mod db_read;
 pub mod db_write;
+mod error;
+mod options;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+pub use db_read::changes::Changes;
+pub use db_read::read_only::ReadOnlyBitCaskPlus;
+pub use db_read::scan::{Keys, Scan, Values};
+pub use db_write::batch::WriteBatch;
+pub use db_write::transaction::Transaction;
+pub use error::Error;
+pub use options::{Options, SyncPolicy};
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
 pub struct DataReader {
     file: Arc<File>,
+    file_num: u64,
     cursor: u64,
+    version: u32,
 }
 
 impl DataReader {
-    pub fn new(f: File, c: u64) -> Self {
-        Self {
+    pub fn new(f: File, file_num: u64) -> Result<Self> {
+        let version = db_format::read_version(&f)?;
+        Ok(Self {
             file: Arc::new(f),
-            cursor: c,
-        }
+            file_num,
+            cursor: db_format::data_start(version),
+            version,
+        })
     }
 
-    pub fn read_data(&self, pos: u64, len: u64) -> io::Result<(u32, Vec<u8>)> {
-        if len >= 10 * COMPACTION_THRESHOLD {
-            return Err(io::Error::other("over capacity"));
-        }
+    pub fn read_data(&self, pos: u64, len: u64) -> Result<(u32, Vec<u8>)> {
         let mut buffer = vec![0u8; len as usize];
         self.file.read_exact_at(&mut buffer, pos)?;
         let expected_crc = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
         Ok((expected_crc, buffer))
     }
+
+    /// Whether the record at `pos` runs past `file_len`, as a torn append does.
+    pub(crate) fn runs_past(&self, pos: u64, file_len: u64) -> Result<bool> {
+        let header_len = db_format::header_len(self.version);
+        if pos + header_len > file_len {
+            return Ok(true);
+        }
+        let mut header = vec![0u8; header_len as usize];
+        self.file.read_exact_at(&mut header, pos)?;
+        let len = db_format::record_len(self.version, &header);
+        Ok(len.is_none_or(|len| len > file_len - pos))
+    }
+
+    pub fn read_command(&self, pos: u64, len: u64) -> Result<Command> {
+        self.read_record(pos, len).map(|(cmd, _)| cmd)
+    }
+
+    pub fn read_record(&self, pos: u64, len: u64) -> Result<(Command, db_format::RecordMeta)> {
+        let (_, buffer) = self.read_data(pos, len)?;
+        db_format::decode_record(self.version, &buffer).ok_or(Error::Corruption {
+            file: self.file_num,
+            offset: pos,
+        })
+    }
 }
 
 impl Iterator for DataReader {
-    type Item = io::Result<(Command, CommandPos)>;
+    type Item = Result<(Command, CommandPos)>;
     fn next(&mut self) -> Option<Self::Item> {
         let pos = self.cursor;
-        let mut header_buf = [0u8; 12];
+        let file_len = match self.file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Some(Err(e.into())),
+        };
+        if pos >= file_len {
+            return None;
+        }
+        let corruption = Error::Corruption {
+            file: self.file_num,
+            offset: pos,
+        };
+
+        // A record running past the end of the file is a torn append.
+        let header_len = db_format::header_len(self.version);
+        if pos + header_len > file_len {
+            return Some(Err(corruption));
+        }
+        let mut header_buf = vec![0u8; header_len as usize];
         if let Err(e) = self.file.read_exact_at(&mut header_buf, pos) {
-            if e.kind() == io::ErrorKind::UnexpectedEof {
-                return None;
+            return Some(Err(e.into()));
+        }
+        let total_len = match db_format::record_len(self.version, &header_buf) {
+            Some(total_len) if file_len - pos >= total_len => total_len,
+            _ => return Some(Err(corruption)),
+        };
+
+        let mut data_buf = vec![0u8; total_len as usize];
+        if let Err(e) = self.file.read_exact_at(&mut data_buf, pos) {
+            return Some(Err(e.into()));
+        }
+        // The records of a batch are read one by one once the whole batch
+        // checks out, a damaged batch is not applied at all.
+        if db_format::is_batch(self.version, &header_buf) {
+            if !db_format::verify_batch(self.version, &data_buf) {
+                return Some(Err(corruption));
             }
-            return Some(Err(e));
-        }
-        let data_len = u64::from_le_bytes(header_buf[4..12].try_into().unwrap());
-        let total_len = 12 + data_len;
-
-        let mut data_buf = vec![0u8; data_len as usize];
-        if let Err(e) = self.file.read_exact_at(&mut data_buf, pos + 12) {
-            return Some(Err(e));
-        }
-        self.cursor += total_len;
-        match serde_json::from_slice::<Command>(&data_buf) {
-            Ok(cmd) => Some(Ok((
-                cmd,
-                CommandPos {
-                    file_num: 0,
-                    pos,
-                    len: total_len,
-                },
-            ))),
-            Err(e) => Some(Err(io::Error::other(e))),
+            self.cursor += db_format::RECORD_HEADER_LEN;
+            return self.next();
+        }
+        match db_format::decode_record(self.version, &data_buf) {
+            Some((cmd, meta)) => {
+                self.cursor += total_len;
+                let expires_at = cmd.expires_at();
+                Some(Ok((
+                    cmd,
+                    CommandPos {
+                        file_num: self.file_num,
+                        pos,
+                        len: total_len,
+                        expires_at,
+                        seqno: meta.seqno,
+                    },
+                )))
+            }
+            None => Some(Err(corruption)),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// Milliseconds since the Unix epoch after which the value is gone.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Command {
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            Command::Remove { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CommandPos {
     file_num: u64,
     pos: u64,
     len: u64,
+    /// Expiry of the value, kept here so expired keys are skipped without a read.
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    seqno: Option<u64>,
 }
 
-#[derive(Debug)]
+impl CommandPos {
+    /// Whether the value is still there at `now`, in milliseconds since the Unix epoch.
+    pub(crate) fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Where and when the current value of a key was written, returned by
+/// [`BitCaskPlus::get_with_meta`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    /// Milliseconds since the Unix epoch, `None` for records of legacy files.
+    pub timestamp: Option<u64>,
+    /// Position of the write among all writes to the store, `None` for
+    /// records written before sequence numbers existed.
+    pub seqno: Option<u64>,
+    pub file: u64,
+    pub offset: u64,
+}
+
+/// A corrupted record found while rebuilding the keydir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRecord {
+    pub file: u64,
+    pub offset: u64,
+    /// Bytes from `offset` to the end of the file that were not loaded.
+    pub skipped_bytes: u64,
+}
+
+/// What `open` found and repaired while rebuilding the keydir.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Torn tail cut off the last data file, usually left by a crash mid-append.
+    pub truncated: Option<CorruptRecord>,
+    /// Corruption other than a torn tail. Records after it are not loaded,
+    /// but the file is left untouched.
+    pub corrupted: Vec<CorruptRecord>,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.truncated.is_none() && self.corrupted.is_empty()
+    }
+}
+
+/// Byte counts of one generation, kept next to its [`DataReader`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Bytes of records the keydir points at.
+    pub live_bytes: u64,
+    /// Bytes of overwritten records, deleted records and tombstones.
+    pub dead_bytes: u64,
+    pub tombstones: u64,
+    /// Highest sequence number in the generation, 0 if no record has one.
+    pub max_seqno: u64,
+}
+
+impl FileStats {
+    /// Percentage of the records in the file that are dead.
+    pub fn fragmentation(&self) -> u64 {
+        match self.live_bytes + self.dead_bytes {
+            0 => 0,
+            total => self.dead_bytes * 100 / total,
+        }
+    }
+}
+
+/// Accounts an appended record, and the one it supersedes, in the stats of
+/// their generations.
+pub(crate) fn account(
+    stats: &mut HashMap<u64, FileStats>,
+    cmd_pos: &CommandPos,
+    tombstone: bool,
+    old_pos: Option<&CommandPos>,
+) {
+    let file_stats = stats.entry(cmd_pos.file_num).or_default();
+    file_stats.max_seqno = file_stats.max_seqno.max(cmd_pos.seqno.unwrap_or(0));
+    if tombstone {
+        file_stats.dead_bytes += cmd_pos.len;
+        file_stats.tombstones += 1;
+    } else {
+        file_stats.live_bytes += cmd_pos.len;
+    }
+    if let Some(old_pos) = old_pos {
+        let file_stats = stats.entry(old_pos.file_num).or_default();
+        file_stats.live_bytes = file_stats.live_bytes.saturating_sub(old_pos.len);
+        file_stats.dead_bytes += old_pos.len;
+    }
+}
+
+/// Moves a record found expired from the live to the dead bytes of its generation.
+pub(crate) fn expire(stats: &mut HashMap<u64, FileStats>, cmd_pos: &CommandPos) {
+    let file_stats = stats.entry(cmd_pos.file_num).or_default();
+    file_stats.live_bytes = file_stats.live_bytes.saturating_sub(cmd_pos.len);
+    file_stats.dead_bytes += cmd_pos.len;
+}
+
+/// Immutable version of the index and of the files it points into.
+///
+/// Both maps share structure between versions, so publishing the next one
+/// after a write costs a few allocations rather than a copy of the index. The
+/// index is ordered by key, which is what range and prefix scans walk.
+#[derive(Debug, Clone, Default)]
+pub struct Keydir {
+    pub(crate) map: imbl::OrdMap<Vec<u8>, CommandPos>,
+    pub(crate) readers: imbl::HashMap<u64, DataReader>,
+}
+
+/// Publishes the keydir `f` makes out of the current one.
+///
+/// Callers hold the writer mutex, which keeps updates from overwriting each
+/// other. Readers keep whatever version they loaded.
+pub(crate) fn update_keydir<R>(keydir: &ArcSwap<Keydir>, f: impl FnOnce(&mut Keydir) -> R) -> R {
+    let mut next = Keydir::clone(&keydir.load());
+    let res = f(&mut next);
+    keydir.store(Arc::new(next));
+    res
+}
+
+/// Handle on an open store.
+///
+/// Clones share the store, so it can be handed to other threads. Reads load
+/// the current [`Keydir`] without taking a lock, writes serialize on the
+/// active file.
+#[derive(Debug, Clone)]
 pub struct BitCaskPlus {
     path: PathBuf,
-    map: Arc<RwLock<HashMap<String, CommandPos>>>,
-    writer: Arc<Mutex<BufWriter<File>>>,
-    readers: Arc<RwLock<HashMap<u64, DataReader>>>,
-    uncompacted: u64,
-    cur_gen: u64,
+    keydir: Arc<ArcSwap<Keydir>>,
+    /// Active generation, `None` when opened read-only. Also guards updates
+    /// of the keydir.
+    writer: Arc<Mutex<Option<db_write::writer::ActiveFile>>>,
+    stats: Arc<Mutex<HashMap<u64, FileStats>>>,
+    /// Highest sequence number of a remove a merge dropped.
+    compacted_seqno: Arc<AtomicU64>,
+    uncompacted: Arc<AtomicU64>,
+    recovery: Arc<RecoveryReport>,
+    options: Options,
+    /// Background merge worker, `None` when opened read-only.
+    compactor: Option<Arc<db_read::compactor::Compactor>>,
+    // Only held so the background syncer stops together with the last handle.
+    _syncer: Option<Arc<db_write::syncer::Syncer>>,
+    // Directory lock, released once the workers above have stopped.
+    _lock: Option<Arc<File>>,
 }
 
-pub fn new_log_file(
-    path: &PathBuf,
+/// Creates generation `gen_num` and registers its reader. The directory is
+/// not synced, callers that need the file to survive a crash do that.
+pub(crate) fn new_log_file(
+    path: &Path,
     gen_num: u64,
-    readers: &mut HashMap<u64, DataReader>,
-) -> io::Result<File> {
+    readers: &mut imbl::HashMap<u64, DataReader>,
+) -> Result<File> {
     let log_path = path.join(format!("{}.db", gen_num));
     let file = OpenOptions::new()
         .read(true)
         .write(true)
         .create_new(true)
         .open(&log_path)?;
-    let reader = DataReader::new(file.try_clone().expect("clone failed"), 0);
+    let mut file = file;
+    file.write_all(&db_format::file_header())?;
+    let reader = DataReader::new(file.try_clone().expect("clone failed"), gen_num)?;
     readers.insert(gen_num, reader);
 
-    let mut file = file;
     file.seek(io::SeekFrom::End(0))?;
     Ok(file)
 }
 
-impl BitCaskPlus {
-    pub fn new(path: PathBuf) -> Self {
-        let log_path = path.join("bitcaskplus.db");
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&log_path)
-            .expect("can't open or create the file");
-        Self {
-            path,
-            map: Arc::new(RwLock::new(HashMap::new())),
-            writer: Arc::new(Mutex::new(BufWriter::new(
-                file.try_clone().expect("clone failed"),
-            ))),
-            readers: Arc::new(RwLock::new(HashMap::new())),
-            uncompacted: 0,
-            cur_gen: 0,
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,7 +349,7 @@ mod tests {
     #[test]
     fn hash_map_works() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
 
         store.set("key1".to_owned(), "value1".to_owned())?;
         store.set("key2".to_owned(), "value2".to_owned())?;
@@ -160,7 +364,7 @@ mod tests {
     #[test]
     fn get_stored_value() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
         store.set("key1".to_owned(), "value1".to_owned())?;
         store.set("key2".to_owned(), "value2".to_owned())?;
 
@@ -181,7 +385,7 @@ mod tests {
     #[test]
     fn overwrite_value() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
 
         store.set("key1".to_string(), "value1".to_string())?;
         assert_eq!(store.get("key1")?, Some("value1".to_string()));
@@ -190,7 +394,7 @@ mod tests {
 
         // Open from disk again and check persistent data.
         drop(store);
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
         assert_eq!(store.get("key1")?, Some("value2".to_string()));
         store.set("key1".to_string(), "value3".to_string())?;
         assert_eq!(store.get("key1")?, Some("value3".to_string()));
@@ -202,7 +406,7 @@ mod tests {
     #[test]
     fn get_non_existent_value() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
 
         store.set("key1".to_string(), "value1".to_string())?;
         assert_eq!(store.get("key2")?, None);
@@ -218,7 +422,7 @@ mod tests {
     #[test]
     fn remove_non_existent_key() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
         assert!(store.remove("key1").is_err());
         Ok(())
     }
@@ -226,7 +430,7 @@ mod tests {
     #[test]
     fn remove_key() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
         store.set("key1".to_string(), "value1".to_string())?;
         assert!(store.remove("key1").is_ok());
         assert_eq!(store.get("key1")?, None);
@@ -238,7 +442,7 @@ mod tests {
     #[test]
     fn compaction() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = BitCaskPlus::open(temp_dir.path())?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
         let dir_size = || {
             let entries = WalkDir::new(temp_dir.path()).into_iter();
             let len: walkdir::Result<u64> = entries
@@ -257,6 +461,7 @@ mod tests {
                 let value = format!("{}", iter);
                 store.set(key, value)?;
             }
+            store.wait_for_compaction()?;
 
             let new_size = dir_size();
             if new_size > current_size {
@@ -276,4 +481,1407 @@ mod tests {
 
         panic!("No compaction detected");
     }
+
+    // Reopen from the hint file of a merged generation, and fall back to a
+    // full scan once the hint is damaged.
+    #[test]
+    fn hint_file() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0")?;
+        store.compaction()?;
+        drop(store);
+
+        let hints: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension() == Some("hint".as_ref()))
+            .map(|e| e.into_path())
+            .collect();
+        assert_eq!(hints.len(), 1);
+        // Header, then 33 bytes plus the key for each of the 99 live keys.
+        let hint_len = std::fs::metadata(&hints[0])?.len();
+        assert_eq!(hint_len, 8 + 99 * 33 + 9 * 4 + 90 * 5);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key42")?, Some("value42".to_string()));
+        drop(store);
+
+        // A key length running past the end of the hint is not allocated.
+        let mut hint = std::fs::read(&hints[0])?;
+        let key_len_at = db_format::FILE_HEADER_LEN as usize + 5;
+        let key_len = hint[key_len_at..key_len_at + 4].to_vec();
+        hint[key_len_at..key_len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&hints[0], &hint)?;
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key42")?, Some("value42".to_string()));
+        drop(store);
+
+        hint[key_len_at..key_len_at + 4].copy_from_slice(&key_len);
+        let last = hint.len() - 1;
+        hint[last] ^= 0xff;
+        std::fs::write(&hints[0], hint)?;
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        for key_id in 1..100 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(&key)?, Some(format!("value{}", key_id)));
+        }
+
+        Ok(())
+    }
+
+    // Files written by the JSON format stay readable and are rewritten in the
+    // binary format by compaction.
+    #[test]
+    fn legacy_json_file() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut legacy = Vec::new();
+        for json_data in [
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            r#"{"Set":{"key":"key2","value":"value2"}}"#,
+            r#"{"Remove":{"key":"key2"}}"#,
+        ] {
+            legacy.extend_from_slice(&crc32fast::hash(json_data.as_bytes()).to_le_bytes());
+            legacy.extend_from_slice(&(json_data.len() as u64).to_le_bytes());
+            legacy.extend_from_slice(json_data.as_bytes());
+        }
+        std::fs::write(temp_dir.path().join("1.db"), legacy)?;
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        assert_eq!(store.get("key2")?, None);
+        store.compaction()?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        drop(store);
+
+        for gen_num in crate::db_read::reader::sorted_file_list(temp_dir.path())? {
+            let file = File::open(temp_dir.path().join(format!("{}.db", gen_num)))?;
+            assert_eq!(db_format::read_version(&file)?, db_format::FORMAT_VERSION);
+        }
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        assert_eq!(store.get("key2")?, None);
+
+        Ok(())
+    }
+
+    // A legacy length too large to add up is corruption, not a panic.
+    #[test]
+    fn legacy_length_overflow() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut garbage = vec![0u8; 4];
+        garbage.extend_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(temp_dir.path().join("1.db"), &garbage)?;
+
+        let corrupt = CorruptRecord {
+            file: 1,
+            offset: 0,
+            skipped_bytes: garbage.len() as u64,
+        };
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.recovery_report().corrupted, vec![corrupt.clone()]);
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.recovery_report().truncated, Some(corrupt));
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+
+        Ok(())
+    }
+
+    // Data in the single file of the first versions is imported as generation 0.
+    #[test]
+    fn legacy_single_file() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let json_data = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&crc32fast::hash(json_data.as_bytes()).to_le_bytes());
+        legacy.extend_from_slice(&(json_data.len() as u64).to_le_bytes());
+        legacy.extend_from_slice(json_data.as_bytes());
+        std::fs::write(temp_dir.path().join("bitcaskplus.db"), legacy)?;
+
+        let store = BitCaskPlus::new(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        assert!(!temp_dir.path().join("bitcaskplus.db").exists());
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key2")?, Some("value2".to_string()));
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![0, 1]
+        );
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        assert_eq!(store.get("key2")?, Some("value2".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn binary_keys_and_values() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        let key = vec![0u8, 159, 146, 150];
+        let value = vec![255u8, 0, 1, 2, 254];
+
+        store.set_bytes(key.clone(), value.clone())?;
+        assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+        store.set_bytes(b"key1".to_vec(), b"value1".to_vec())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+
+        drop(store);
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(&key)?, Some(value));
+        store.remove_bytes(&key)?;
+        assert_eq!(store.get_bytes(&key)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn typed_errors() -> Result<()> {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<Error>();
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert!(matches!(store.remove("key1"), Err(Error::KeyNotFound)));
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        let gen_num = *crate::db_read::reader::sorted_file_list(temp_dir.path())?
+            .first()
+            .unwrap();
+        let log_path = temp_dir.path().join(format!("{}.db", gen_num));
+        let mut data = std::fs::read(&log_path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&log_path, data)?;
+
+        let cmd = DataReader::new(File::open(&log_path)?, gen_num)?.next();
+        assert!(matches!(
+            cmd,
+            Some(Err(Error::Corruption { file, offset }))
+                if file == gen_num && offset == db_format::FILE_HEADER_LEN
+        ));
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, None);
+
+        Ok(())
+    }
+
+    // A torn append at the end of the last file is cut off on open, while
+    // corruption in older files is only reported.
+    #[test]
+    fn recover_torn_tail() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert!(store.recovery_report().is_clean());
+        drop(store);
+
+        let log_path = temp_dir.path().join("1.db");
+        let good_len = std::fs::metadata(&log_path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&log_path)?;
+        file.write_all(
+            &db_format::encode(
+                &Command::Set {
+                    key: b"key3".to_vec(),
+                    value: b"value3".to_vec(),
+                    expires_at: None,
+                },
+                0,
+                None,
+            )[..10],
+        )?;
+        drop(file);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(
+            store.recovery_report().truncated,
+            Some(CorruptRecord {
+                file: 1,
+                offset: good_len,
+                skipped_bytes: 10,
+            })
+        );
+        assert_eq!(std::fs::metadata(&log_path)?.len(), good_len);
+        assert_eq!(store.get("key2")?, Some("value2".to_string()));
+        assert_eq!(store.get("key3")?, None);
+        // Appends continue in generation 1, right behind the last good record.
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(temp_dir.path().read_dir()?.count(), 3);
+        drop(store);
+        let full_len = std::fs::metadata(&log_path)?.len();
+        assert!(full_len > good_len);
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert!(store.recovery_report().is_clean());
+        assert_eq!(store.get("key3")?, Some("value3".to_string()));
+        drop(store);
+
+        // A full generation 1 rolls the next write over to generation 2.
+        let store =
+            BitCaskPlus::open_with(temp_dir.path(), Options::new().max_file_size(full_len))?;
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        // Damage the first record of generation 1, which is no longer the last.
+        let mut data = std::fs::read(&log_path)?;
+        data[db_format::FILE_HEADER_LEN as usize + 4] ^= 0xff;
+        std::fs::write(&log_path, data)?;
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        let report = store.recovery_report();
+        assert_eq!(report.truncated, None);
+        assert_eq!(
+            report.corrupted,
+            vec![CorruptRecord {
+                file: 1,
+                offset: db_format::FILE_HEADER_LEN,
+                skipped_bytes: full_len - db_format::FILE_HEADER_LEN,
+            }]
+        );
+        assert_eq!(std::fs::metadata(&log_path)?.len(), full_len);
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key4")?, Some("value4".to_string()));
+
+        Ok(())
+    }
+
+    // A damaged record inside the last file is no torn tail, the file is
+    // left as it is and writes go on in a new generation.
+    #[test]
+    fn recover_mid_file_corruption() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        drop(store);
+
+        let log_path = temp_dir.path().join("1.db");
+        let mut data = std::fs::read(&log_path)?;
+        let len = data.len() as u64;
+        data[db_format::FILE_HEADER_LEN as usize + 4] ^= 0xff;
+        std::fs::write(&log_path, data)?;
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        let report = store.recovery_report();
+        assert_eq!(report.truncated, None);
+        assert_eq!(
+            report.corrupted,
+            vec![CorruptRecord {
+                file: 1,
+                offset: db_format::FILE_HEADER_LEN,
+                skipped_bytes: len - db_format::FILE_HEADER_LEN,
+            }]
+        );
+        assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+        store.set("key5".to_owned(), "value5".to_owned())?;
+        drop(store);
+
+        assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+        assert!(temp_dir.path().join("2.db").exists());
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key5")?, Some("value5".to_string()));
+        assert_eq!(store.recovery_report().corrupted.len(), 1);
+
+        // Merges leave the damaged generation alone.
+        store.compaction()?;
+        assert_eq!(std::fs::read(&log_path)?.len() as u64, len);
+        assert_eq!(store.get("key5")?, Some("value5".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_policies() -> Result<()> {
+        for policy in [
+            SyncPolicy::SyncEveryWrite,
+            SyncPolicy::SyncEvery(std::time::Duration::from_millis(10)),
+            SyncPolicy::SyncEveryNBytes(64),
+            SyncPolicy::OsManaged,
+        ] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = Options::new().sync_policy(policy);
+            let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+            for key_id in 0..10 {
+                store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            }
+            store.flush()?;
+            store.sync()?;
+            store.compaction()?;
+            store.set("key10".to_owned(), "value10".to_owned())?;
+            drop(store);
+
+            let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+            for key_id in 0..=10 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(&key)?, Some(format!("value{}", key_id)));
+            }
+        }
+        Ok(())
+    }
+
+    // A background sync that failed is reported by the next write or sync.
+    #[test]
+    fn background_sync_error() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options =
+            Options::new().sync_policy(SyncPolicy::SyncEvery(std::time::Duration::from_secs(3600)));
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let fail = || {
+            store.writer.lock().unwrap().as_mut().unwrap().sync_error =
+                Some(Error::Io(io::Error::other("simulated sync failure")));
+        };
+        fail();
+        assert!(matches!(
+            store.set("key2".to_owned(), "value2".to_owned()),
+            Err(Error::Io(_))
+        ));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        fail();
+        assert!(matches!(store.sync(), Err(Error::Io(_))));
+        store.sync()?;
+        assert_eq!(store.get("key2")?, Some("value2".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn options() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let invalid = Options::new().compaction_threshold(0);
+        assert!(matches!(
+            BitCaskPlus::open_with(temp_dir.path(), invalid),
+            Err(Error::InvalidOptions(_))
+        ));
+        let missing = temp_dir.path().join("missing");
+        assert!(BitCaskPlus::open_with(&missing, Options::new().create_dir(false)).is_err());
+        assert!(!missing.exists());
+
+        let options = Options::new().max_key_size(8).max_value_size(16);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+        assert!(matches!(
+            store.set("a long key".to_owned(), "value".to_owned()),
+            Err(Error::KeyTooLarge { len: 10, max: 8 })
+        ));
+        assert!(matches!(
+            store.set("key".to_owned(), "a rather long value".to_owned()),
+            Err(Error::ValueTooLarge { len: 19, max: 16 })
+        ));
+        store.set("key".to_owned(), "value".to_owned())?;
+        drop(store);
+
+        assert!(matches!(
+            BitCaskPlus::open_with(temp_dir.path(), Options::new().max_key_size(4)),
+            Err(Error::OptionsMismatch {
+                name: "max_key_size",
+                stored: 8,
+                requested: 4,
+            })
+        ));
+        let store = BitCaskPlus::open_with(temp_dir.path(), options.max_value_size(32))?;
+        assert_eq!(store.get("key")?, Some("value".to_string()));
+        drop(store);
+
+        // Readers are not held to the limits of the writer.
+        let large = Options::new().max_value_size(64 * 1024 * 1024);
+        drop(BitCaskPlus::open_with(temp_dir.path(), large)?);
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.get("key")?, Some("value".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_option() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        let files_before = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+
+        let store = BitCaskPlus::open_with(temp_dir.path(), Options::new().read_only(true))?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+        assert!(matches!(
+            store.set("key2".to_owned(), "value2".to_owned()),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(store.remove("key1"), Err(Error::ReadOnly)));
+        assert!(matches!(store.compaction(), Err(Error::ReadOnly)));
+        drop(store);
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            files_before
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_active_file() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().max_file_size(256);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        drop(store);
+
+        let file_list = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+        assert!(file_list.len() > 10);
+        for gen_num in &file_list {
+            let path = temp_dir.path().join(format!("{}.db", gen_num));
+            assert!(std::fs::metadata(path)?.len() <= 256);
+        }
+
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(&key)?, Some(format!("value{}", key_id)));
+        }
+
+        Ok(())
+    }
+
+    // Overwrites past the threshold are merged on the background thread, which
+    // holds off while paused.
+    #[test]
+    fn background_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().compaction_threshold(1024);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        store.pause_compaction();
+        for iter in 0..20 {
+            for key_id in 0..10 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        store.wait_for_compaction()?;
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![1, 3]
+        );
+
+        store.resume_compaction();
+        store.wait_for_compaction()?;
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![2, 3]
+        );
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(&key)?, Some("value19".to_string()));
+        }
+
+        store.set("key0".to_owned(), "value20".to_owned())?;
+        store.remove("key1")?;
+        store.trigger_compaction()?;
+        store.wait_for_compaction()?;
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, Some("value20".to_string()));
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key9")?, Some("value19".to_string()));
+
+        Ok(())
+    }
+
+    // Only generations past the fragmentation threshold are rewritten, and
+    // tombstones survive as long as an older generation may hold their key,
+    // hinted like any other record.
+    #[test]
+    fn incremental_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().max_file_size(512).frag_threshold(50);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        store.set("gone".to_owned(), "value".to_owned())?;
+        for key_id in 0..10 {
+            store.set(format!("cold{}", key_id), format!("value{}", key_id))?;
+        }
+        for iter in 0..100 {
+            store.set(format!("hot{}", iter % 5), format!("value{}", iter))?;
+        }
+        store.remove("gone")?;
+        for iter in 100..120 {
+            store.set(format!("hot{}", iter % 5), format!("value{}", iter))?;
+        }
+
+        let before = store.file_stats();
+        assert!(before[&1].fragmentation() <= 50);
+        let gen1_len = std::fs::metadata(temp_dir.path().join("1.db"))?.len();
+        assert_eq!(
+            before[&1].live_bytes + before[&1].dead_bytes,
+            gen1_len - db_format::FILE_HEADER_LEN
+        );
+        store.trigger_compaction()?;
+        store.wait_for_compaction()?;
+
+        let after = store.file_stats();
+        assert_eq!(after[&1], before[&1]);
+        for (gen_num, file_stats) in &before {
+            if file_stats.fragmentation() > 50 {
+                assert!(!after.contains_key(gen_num));
+            }
+        }
+        let merged: Vec<_> = after.iter().filter(|(_, s)| s.tombstones == 1).collect();
+        assert_eq!(merged.len(), 1);
+        let (&merged_gen, merged_stats) = (merged[0].0, merged[0].1.clone());
+        // The tombstone was carried into the merge output, and is part of its hint.
+        assert!(!before.contains_key(&merged_gen));
+        let hint_path = temp_dir.path().join(format!("{}.db.hint", merged_gen));
+        assert!(hint_path.is_file());
+        // A carried remove is still handed out.
+        let removes = store
+            .changes_since(0)?
+            .filter(|change| matches!(change, Ok((_, Command::Remove { key })) if key == b"gone"));
+        assert_eq!(removes.count(), 1);
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.file_stats()[&merged_gen], merged_stats);
+        assert_eq!(store.get("gone")?, None);
+        assert_eq!(store.get("cold3")?, Some("value3".to_string()));
+        assert_eq!(store.get("hot4")?, Some("value119".to_string()));
+
+        Ok(())
+    }
+
+    // Triggers that find no generation worth merging leave the active file
+    // alone, instead of sealing it into one more small generation.
+    #[test]
+    fn compaction_trigger_without_candidates() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().compaction_threshold(1).frag_threshold(90);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), "value0".to_owned())?;
+        }
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), "value1".to_owned())?;
+            store.wait_for_compaction()?;
+        }
+        store.trigger_compaction()?;
+        store.wait_for_compaction()?;
+        let stats = store.file_stats();
+        assert_eq!(stats.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert!(stats[&1].dead_bytes > 0);
+
+        Ok(())
+    }
+
+    // A merge killed at any step leaves either its inputs or its output behind,
+    // never both and never a half-written output.
+    #[test]
+    fn crash_during_compaction() -> Result<()> {
+        use crate::db_read::compaction::{CRASH_AFTER, MergeStep};
+
+        for step in [
+            MergeStep::Started,
+            MergeStep::OutputWritten,
+            MergeStep::Committed,
+            MergeStep::InputDeleted,
+        ] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = Options::new().max_file_size(256);
+            let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+            store.set("gone".to_owned(), "value".to_owned())?;
+            for iter in 0..20 {
+                store.set(format!("key{}", iter % 8), format!("value{}", iter))?;
+            }
+            store.remove("gone")?;
+            let inputs = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+            let compaction_gen = inputs.last().unwrap() + 1;
+
+            CRASH_AFTER.with(|c| c.set(Some(step)));
+            let res = store.compaction();
+            CRASH_AFTER.with(|c| c.set(None));
+            assert!(res.is_err());
+            drop(store);
+
+            let check = |store: &BitCaskPlus| -> Result<()> {
+                assert_eq!(store.get("gone")?, None);
+                for iter in 12..20 {
+                    let key = format!("key{}", iter % 8);
+                    assert_eq!(store.get(&key)?, Some(format!("value{}", iter)));
+                }
+                Ok(())
+            };
+            let read_only = Options::new().read_only(true);
+            check(&BitCaskPlus::open_with(temp_dir.path(), read_only)?)?;
+            check(&BitCaskPlus::open_with(temp_dir.path(), options)?)?;
+
+            let file_list = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+            let committed = matches!(step, MergeStep::Committed | MergeStep::InputDeleted);
+            assert_eq!(file_list.contains(&compaction_gen), committed);
+            for gen_num in &inputs {
+                assert_eq!(file_list.contains(gen_num), !committed);
+            }
+            let manifest = temp_dir.path().join(format!("{}.merge", compaction_gen));
+            assert!(!manifest.exists());
+        }
+
+        Ok(())
+    }
+
+    // Clones of one handle write and read from several threads while
+    // background compaction merges underneath them.
+    #[test]
+    fn shared_across_threads() -> Result<()> {
+        fn assert_handle<T: Clone + Send + Sync + 'static>() {}
+        assert_handle::<BitCaskPlus>();
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new()
+            .compaction_threshold(4096)
+            .max_file_size(2048);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        let handles: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for iter in 0..200 {
+                        let key = format!("key{}-{}", thread_id, iter % 10);
+                        store.set(key.clone(), format!("value{}", iter))?;
+                        assert_eq!(store.get(&key)?, Some(format!("value{}", iter)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        store.wait_for_compaction()?;
+
+        let check = |store: &BitCaskPlus| -> Result<()> {
+            for thread_id in 0..4 {
+                for iter in 190..200 {
+                    let key = format!("key{}-{}", thread_id, iter % 10);
+                    assert_eq!(store.get(&key)?, Some(format!("value{}", iter)));
+                }
+            }
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+        check(&BitCaskPlus::open(temp_dir.path())?)
+    }
+
+    #[test]
+    fn exclusive_lock() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let files_before = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+        assert!(matches!(
+            BitCaskPlus::open(temp_dir.path()),
+            Err(Error::Locked)
+        ));
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            files_before
+        );
+        let reader = BitCaskPlus::open_with(temp_dir.path(), Options::new().read_only(true))?;
+        assert_eq!(reader.get("key1")?, Some("value1".to_string()));
+        drop(reader);
+
+        // Clones keep the lock until the last one goes.
+        let clone = store.clone();
+        drop(store);
+        assert!(matches!(
+            BitCaskPlus::open(temp_dir.path()),
+            Err(Error::Locked)
+        ));
+        drop(clone);
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_string()));
+
+        Ok(())
+    }
+
+    // A read-only handle next to a writer sees new records, generations and
+    // merges after `refresh`, and never touches the directory itself.
+    #[test]
+    fn read_only_refresh() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open_with(temp_dir.path(), Options::new().max_file_size(256))?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "value0".to_owned())?;
+        }
+
+        let reader = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(reader.get("key9")?, Some("value0".to_string()));
+        let gens_before = reader.file_stats().len();
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "value1".to_owned())?;
+        }
+        store.remove("key0")?;
+        assert_eq!(reader.get("key9")?, Some("value0".to_string()));
+        reader.refresh()?;
+        assert!(reader.file_stats().len() > gens_before);
+        assert_eq!(reader.get("key0")?, None);
+        assert_eq!(reader.get("key9")?, Some("value1".to_string()));
+
+        store.compaction()?;
+        store.set("key10".to_owned(), "value1".to_owned())?;
+        reader.refresh()?;
+        assert_eq!(reader.file_stats().len(), 2);
+        assert_eq!(reader.get("key0")?, None);
+        assert_eq!(reader.get("key10")?, Some("value1".to_string()));
+
+        // A reader opened after the merge loaded it from its hint, refreshing
+        // must not read the merged copies again.
+        store.set("key9".to_owned(), "value2".to_owned())?;
+        let reader = BitCaskPlus::open_read_only(temp_dir.path())?;
+        store.set("key11".to_owned(), "value2".to_owned())?;
+        reader.refresh()?;
+        assert_eq!(reader.get("key9")?, Some("value2".to_string()));
+        assert_eq!(reader.get("key11")?, Some("value2".to_string()));
+        assert_eq!(reader.file_stats(), store.file_stats());
+        drop(store);
+
+        let list_dir = || -> Result<Vec<std::path::PathBuf>> {
+            let mut paths = std::fs::read_dir(temp_dir.path())?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            paths.sort();
+            Ok(paths)
+        };
+        let files_before = list_dir()?;
+        let reader = BitCaskPlus::open_read_only(temp_dir.path())?;
+        reader.refresh()?;
+        assert_eq!(reader.get("key5")?, Some("value1".to_string()));
+        assert_eq!(list_dir()?, files_before);
+
+        Ok(())
+    }
+
+    // Read-only opens and refreshes racing the merges of a writer retry
+    // instead of failing on a generation deleted under them.
+    #[test]
+    fn read_only_open_during_merges() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open_with(temp_dir.path(), Options::new().max_file_size(512))?;
+        store.set("stable".to_owned(), "value".to_owned())?;
+        let writer = {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 0..50 {
+                    for key_id in 0..10 {
+                        store.set(format!("key{}", key_id), format!("value{}", iter))?;
+                    }
+                    store.compaction()?;
+                }
+                Ok(())
+            })
+        };
+        while !writer.is_finished() {
+            let reader = BitCaskPlus::open_read_only(temp_dir.path())?;
+            assert_eq!(reader.get("stable")?, Some("value".to_string()));
+            reader.refresh()?;
+            assert_eq!(reader.get("stable")?, Some("value".to_string()));
+        }
+        writer.join().unwrap()?;
+
+        Ok(())
+    }
+
+    // Opening never creates a generation, writes go on in the last one.
+    #[test]
+    fn reopen_reuses_last_generation() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for _ in 0..3 {
+            drop(BitCaskPlus::open(temp_dir.path())?);
+        }
+        assert!(crate::db_read::reader::sorted_file_list(temp_dir.path())?.is_empty());
+
+        for key_id in 0..3 {
+            let store = BitCaskPlus::open(temp_dir.path())?;
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![1]
+        );
+
+        // A merge output keeps its hint, so the next write starts a new generation.
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.compaction()?;
+        drop(store);
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![2]
+        );
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(
+            crate::db_read::reader::sorted_file_list(temp_dir.path())?,
+            vec![2, 3]
+        );
+        for key_id in 0..4 {
+            assert_eq!(
+                store.get(&format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_and_prefix_scans() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        for sensor in 1..=3 {
+            for ts in 0..5 {
+                store.set(format!("sensor/{}/{}", sensor, ts), format!("{}", ts))?;
+            }
+        }
+        store.set_bytes(vec![0xff, 0xff], b"max".to_vec())?;
+        store.remove("sensor/2/3")?;
+        fn keys(scan: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>) -> Result<Vec<String>> {
+            scan.map(|entry| Ok(String::from_utf8(entry?.0)?)).collect()
+        }
+
+        assert_eq!(
+            keys(store.prefix("sensor/2/"))?,
+            vec!["sensor/2/0", "sensor/2/1", "sensor/2/2", "sensor/2/4"]
+        );
+        assert_eq!(
+            keys(store.range("sensor/1/3".."sensor/2/1"))?,
+            vec!["sensor/1/3", "sensor/1/4", "sensor/2/0"]
+        );
+        assert_eq!(
+            keys(store.range("sensor/3/3"..="sensor/3/4"))?,
+            vec!["sensor/3/3", "sensor/3/4"]
+        );
+        assert_eq!(
+            keys(store.prefix("sensor/3/").rev())?,
+            vec![
+                "sensor/3/4",
+                "sensor/3/3",
+                "sensor/3/2",
+                "sensor/3/1",
+                "sensor/3/0"
+            ]
+        );
+        assert!(keys(store.range("b".."a"))?.is_empty());
+        assert_eq!(
+            store.prefix([0xff]).collect::<Result<Vec<_>>>()?,
+            vec![(vec![0xff, 0xff], b"max".to_vec())]
+        );
+
+        // Both ends meet in the middle without returning an entry twice.
+        let mut scan = store.prefix("sensor/1/");
+        assert_eq!(scan.next().transpose()?.unwrap().1, b"0");
+        assert_eq!(scan.next_back().transpose()?.unwrap().1, b"4");
+        assert_eq!(keys(scan)?, vec!["sensor/1/1", "sensor/1/2", "sensor/1/3"]);
+
+        // A scan sees the store as it was when it started, even across a merge.
+        let scan = store.prefix("sensor/1/");
+        store.remove("sensor/1/0")?;
+        store.set("sensor/1/5".to_owned(), "5".to_owned())?;
+        store.compaction()?;
+        let entries = scan.collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0], (b"sensor/1/0".to_vec(), b"0".to_vec()));
+        assert_eq!(keys(store.prefix("sensor/1/"))?.len(), 5);
+        assert_eq!(keys(store.prefix("sensor/1/"))?[0], "sensor/1/1");
+
+        drop(store);
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(
+            keys(store.range("sensor/2/4".."sensor/3"))?,
+            vec!["sensor/2/4"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_live_entries() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.iter().count(), 0);
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("key3".to_owned(), "new3".to_owned())?;
+        store.remove("key5")?;
+
+        let keys: Vec<Vec<u8>> = store.keys().collect();
+        assert_eq!(keys.len(), 9);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!keys.contains(&b"key5".to_vec()));
+        let values = store.values().collect::<Result<Vec<_>>>()?;
+        assert_eq!(values[3], b"new3");
+        let entries = store.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            keys.iter().cloned().zip(values).collect::<Vec<_>>()
+        );
+        assert_eq!(store.keys().next_back(), Some(b"key9".to_vec()));
+
+        // Writes and merges after an iterator was created do not show up in it.
+        let (keys, values) = (store.keys(), store.values());
+        store.remove("key0")?;
+        store.set("key10".to_owned(), "value10".to_owned())?;
+        store.compaction()?;
+        assert_eq!(keys.count(), 9);
+        let values = values.collect::<Result<Vec<_>>>()?;
+        assert_eq!(values.first(), Some(&b"value0".to_vec()));
+        assert_eq!(store.keys().count(), 9);
+        assert_eq!(store.keys().next(), Some(b"key1".to_vec()));
+
+        drop(store);
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.iter().count(), 9);
+        assert_eq!(store.values().next().transpose()?, Some(b"value1".to_vec()));
+
+        Ok(())
+    }
+
+    // A batch is loaded completely or not at all.
+    #[test]
+    fn write_batch() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().max_value_size(1024);
+        let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+        store.set("object".to_owned(), "v1".to_owned())?;
+        store.set("index/v1".to_owned(), "object".to_owned())?;
+        store.write_batch(WriteBatch::new())?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put("object", "v2")
+            .delete("index/v1")
+            .put("index/v2", "object")
+            .delete("missing");
+        assert_eq!(batch.len(), 4);
+        store.write_batch(batch)?;
+        assert_eq!(store.get("object")?, Some("v2".to_string()));
+        assert_eq!(store.get("index/v1")?, None);
+        assert_eq!(store.get("index/v2")?, Some("object".to_string()));
+        let mut too_large = WriteBatch::new();
+        too_large.put("key", vec![0u8; 2048]);
+        drop(store);
+
+        let store = BitCaskPlus::open_with(temp_dir.path(), options.clone())?;
+        assert!(store.recovery_report().is_clean());
+        assert_eq!(store.get("object")?, Some("v2".to_string()));
+        assert_eq!(store.get("index/v1")?, None);
+        assert_eq!(store.get("index/v2")?, Some("object".to_string()));
+        let good_len = std::fs::metadata(temp_dir.path().join("1.db"))?.len();
+
+        // Tear the next batch in the middle of its second record.
+        let mut batch = WriteBatch::new();
+        batch.put("object", "v3").put("index/v3", "object");
+        store.write_batch(batch)?;
+        drop(store);
+        let log_path = temp_dir.path().join("1.db");
+        let torn_len = std::fs::metadata(&log_path)?.len() - 5;
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(torn_len)?;
+
+        let store = BitCaskPlus::open_with(temp_dir.path(), options)?;
+        assert_eq!(
+            store.recovery_report().truncated,
+            Some(CorruptRecord {
+                file: 1,
+                offset: good_len,
+                skipped_bytes: torn_len - good_len,
+            })
+        );
+        assert_eq!(store.get("object")?, Some("v2".to_string()));
+        assert_eq!(store.get("index/v3")?, None);
+        assert!(matches!(
+            store.write_batch(too_large),
+            Err(Error::ValueTooLarge { .. })
+        ));
+
+        // Batched records are merged like any other.
+        store.compaction()?;
+        assert_eq!(store.get("object")?, Some("v2".to_string()));
+        assert_eq!(store.get("index/v2")?, Some("object".to_string()));
+        assert_eq!(store.keys().count(), 2);
+        drop(store);
+
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.get("index/v2")?, Some("object".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn transactions() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("stock".to_owned(), "0".to_owned())?;
+        store.set("other".to_owned(), "0".to_owned())?;
+
+        // Reads see the transaction's own writes, which only land on commit.
+        let seen = store.transaction(|tx| {
+            tx.set("stock".to_owned(), "1".to_owned());
+            tx.remove("other")?;
+            assert!(matches!(tx.remove("other"), Err(Error::KeyNotFound)));
+            assert_eq!(store.get("stock")?, Some("0".to_string()));
+            tx.get("stock")
+        })?;
+        assert_eq!(seen, Some("1".to_string()));
+        assert_eq!(store.get("stock")?, Some("1".to_string()));
+        assert_eq!(store.get("other")?, None);
+
+        // A key read by the transaction changes before it commits.
+        let res = store.transaction(|tx| {
+            tx.get("stock")?;
+            store.set("stock".to_owned(), "5".to_owned())?;
+            tx.set("stock".to_owned(), "2".to_owned());
+            tx.set("other".to_owned(), "2".to_owned());
+            Ok(())
+        });
+        assert!(matches!(res, Err(Error::Conflict)));
+        assert_eq!(store.get("stock")?, Some("5".to_string()));
+        assert_eq!(store.get("other")?, None);
+
+        // So does a key the transaction found absent.
+        let res = store.transaction(|tx| {
+            if tx.get("other")?.is_none() {
+                tx.set("stock".to_owned(), "0".to_owned());
+            }
+            store.set("other".to_owned(), "3".to_owned())?;
+            Ok(())
+        });
+        assert!(matches!(res, Err(Error::Conflict)));
+
+        // Keys that were only written do not conflict, a failing closure writes nothing.
+        store.transaction(|tx| {
+            store.set("other".to_owned(), "4".to_owned())?;
+            tx.set("other".to_owned(), "6".to_owned());
+            Ok(())
+        })?;
+        assert_eq!(store.get("other")?, Some("6".to_string()));
+        let res: Result<()> = store.transaction(|tx| {
+            tx.set("other".to_owned(), "7".to_owned());
+            Err(Error::KeyNotFound)
+        });
+        assert!(matches!(res, Err(Error::KeyNotFound)));
+        assert_eq!(store.get("other")?, Some("6".to_string()));
+
+        // Retried increments from several threads lose no update.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..25 {
+                        loop {
+                            let res = store.transaction(|tx| {
+                                let stock: u64 = tx.get("stock")?.unwrap().parse().unwrap();
+                                tx.set("stock".to_owned(), (stock + 1).to_string());
+                                Ok(())
+                            });
+                            match res {
+                                Err(Error::Conflict) => continue,
+                                res => break res?,
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in threads {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(store.get("stock")?, Some("105".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+
+        assert!(store.set_if_absent(b"leader".to_vec(), b"a".to_vec())?);
+        assert!(!store.set_if_absent(b"leader".to_vec(), b"b".to_vec())?);
+        assert_eq!(store.get("leader")?, Some("a".to_string()));
+
+        assert!(!store.compare_and_swap(b"leader", Some(b"b"), Some(b"c".to_vec()))?);
+        assert!(!store.compare_and_swap(b"leader", None, Some(b"c".to_vec()))?);
+        assert!(store.compare_and_swap(b"leader", Some(b"a"), Some(b"c".to_vec()))?);
+        assert_eq!(store.get("leader")?, Some("c".to_string()));
+        assert!(store.compare_and_swap(b"leader", Some(b"c"), None)?);
+        assert_eq!(store.get("leader")?, None);
+        assert!(store.compare_and_swap(b"leader", None, None)?);
+        assert!(matches!(
+            store.compare_and_swap(b"leader", None, Some(vec![0u8; 11 * 1024 * 1024])),
+            Err(Error::ValueTooLarge { .. })
+        ));
+
+        assert_eq!(
+            store.update(b"count", |old| {
+                assert_eq!(old, None);
+                Some(b"1".to_vec())
+            })?,
+            Some(b"1".to_vec())
+        );
+        assert_eq!(store.update(b"count", |_| None)?, None);
+        assert_eq!(store.get("count")?, None);
+        assert_eq!(store.update(b"count", |_| None)?, None);
+
+        // Only one of several racing candidates becomes leader, and counters
+        // updated from several threads lose no increment.
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<bool> {
+                    for _ in 0..25 {
+                        store.update(b"count", |old| {
+                            let count: u64 =
+                                old.map_or(0, |old| String::from_utf8_lossy(old).parse().unwrap());
+                            Some((count + 1).to_string().into_bytes())
+                        })?;
+                    }
+                    store.set_if_absent(b"leader".to_vec(), vec![id])
+                })
+            })
+            .collect();
+        let mut leaders = 0;
+        for handle in threads {
+            leaders += handle.join().unwrap()? as u32;
+        }
+        assert_eq!(leaders, 1);
+        assert_eq!(store.get("count")?, Some("100".to_string()));
+        drop(store);
+
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.get("count")?, Some("100".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expiring_values() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        let long = std::time::Duration::from_secs(3600);
+        let short = std::time::Duration::from_millis(20);
+        store.set_with_ttl("session1".to_owned(), "token1".to_owned(), long)?;
+        store.set_with_ttl("session2".to_owned(), "token2".to_owned(), short)?;
+        store.set_with_ttl("session3".to_owned(), "token3".to_owned(), short)?;
+        store.set_with_ttl("session4".to_owned(), "token4".to_owned(), short)?;
+        store.set("session4".to_owned(), "forever".to_owned())?;
+        assert_eq!(store.get("session2")?, Some("token2".to_string()));
+        let scan = store.keys();
+        std::thread::sleep(short * 2);
+
+        assert_eq!(store.get("session1")?, Some("token1".to_string()));
+        assert_eq!(store.get("session2")?, None);
+        assert_eq!(store.get("session4")?, Some("forever".to_string()));
+        assert_eq!(scan.count(), 4);
+        assert_eq!(store.keys().count(), 2);
+        assert!(matches!(store.remove("session2"), Err(Error::KeyNotFound)));
+        assert!(store.set_if_absent(b"session2".to_vec(), b"token5".to_vec())?);
+        assert_eq!(store.get("session2")?, Some("token5".to_string()));
+        assert_eq!(store.purge_expired()?, 1);
+        assert_eq!(store.keydir.load().map.len(), 3);
+        drop(store);
+
+        // Expired values are not loaded, a merge drops them for good.
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set_with_ttl("session6".to_owned(), "token6".to_owned(), short)?;
+        drop(store);
+        std::thread::sleep(short * 2);
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert!(!store.keydir.load().map.contains_key(b"session6".as_slice()));
+        store.set_with_ttl("session7".to_owned(), "token7".to_owned(), short)?;
+        std::thread::sleep(short * 2);
+        store.compaction()?;
+        assert!(!store.keydir.load().map.contains_key(b"session7".as_slice()));
+        let keys: Vec<Vec<u8>> = store.keys().collect();
+        assert_eq!(keys, [b"session1", b"session2", b"session4"]);
+
+        Ok(())
+    }
+
+    // Dropping an expired value while an older generation still holds the
+    // key leaves a tombstone behind.
+    #[test]
+    fn expired_value_over_older_generation() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("key".to_owned(), "old".to_owned())?;
+        for key_id in 0..4 {
+            store.set(format!("filler{}", key_id), "x".repeat(100))?;
+        }
+        store.compaction()?;
+        store.set_with_ttl(
+            "key".to_owned(),
+            "new".to_owned(),
+            std::time::Duration::from_millis(1),
+        )?;
+        for value in ["1", "2", "3"] {
+            store.set("other".to_owned(), value.to_owned())?;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        store.trigger_compaction()?;
+        store.wait_for_compaction()?;
+        let file_list = crate::db_read::reader::sorted_file_list(temp_dir.path())?;
+        assert_eq!(file_list, vec![2, 4]);
+        assert_eq!(store.get("key")?, None);
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        assert_eq!(store.get("key")?, None);
+        assert_eq!(store.get("other")?, Some("3".to_string()));
+        assert_eq!(store.keys().count(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn record_meta() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let json_data = r#"{"Set":{"key":"legacy","value":"value"}}"#;
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&crc32fast::hash(json_data.as_bytes()).to_le_bytes());
+        legacy.extend_from_slice(&(json_data.len() as u64).to_le_bytes());
+        legacy.extend_from_slice(json_data.as_bytes());
+        std::fs::write(temp_dir.path().join("1.db"), legacy)?;
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        let (value, meta) = store.get_with_meta(b"legacy")?.unwrap();
+        assert_eq!(value, b"value");
+        assert_eq!((meta.timestamp, meta.seqno), (None, None));
+        assert_eq!((meta.file, meta.offset), (1, 0));
+
+        let before = db_format::timestamp();
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.put("key3", "value3").put("key1", "value4");
+        store.write_batch(batch)?;
+        let (value, meta) = store.get_with_meta(b"key1")?.unwrap();
+        assert_eq!(value, b"value4");
+        assert_eq!(meta.seqno, Some(4));
+        assert!(meta.timestamp.unwrap() >= before);
+        assert_eq!(meta.file, 2);
+        let meta2 = store.get_with_meta(b"key2")?.unwrap().1;
+        assert_eq!(meta2.seqno, Some(2));
+        assert!(meta2.offset > db_format::FILE_HEADER_LEN);
+        assert_eq!(store.get_with_meta(b"key3")?.unwrap().1.seqno, Some(3));
+        store.remove("key2")?;
+        assert_eq!(store.get_with_meta(b"key2")?, None);
+        drop(store);
+
+        // Numbering goes on after a reopen, and a merge keeps the numbers and
+        // write times.
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("key2".to_owned(), "value5".to_owned())?;
+        assert_eq!(store.get_with_meta(b"key2")?.unwrap().1.seqno, Some(6));
+        let written = store.get_with_meta(b"key1")?.unwrap().1.timestamp;
+        store.compaction()?;
+        let meta = store.get_with_meta(b"key1")?.unwrap().1;
+        assert_eq!(meta.seqno, Some(4));
+        assert_eq!(meta.timestamp, written);
+        assert_eq!(meta.file, 3);
+        let meta = store.get_with_meta(b"legacy")?.unwrap().1;
+        assert_eq!((meta.timestamp, meta.seqno), (None, None));
+        assert_eq!(meta.file, 3);
+        drop(store);
+
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert_eq!(store.get_with_meta(b"key3")?.unwrap().1.seqno, Some(3));
+        assert_eq!(store.get_with_meta(b"missing")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes_since() -> Result<()> {
+        fn changes(changes: Changes) -> Result<Vec<(u64, String, Option<String>)>> {
+            changes
+                .map(|change| {
+                    Ok(match change? {
+                        (seqno, Command::Set { key, value, .. }) => (
+                            seqno,
+                            String::from_utf8(key)?,
+                            Some(String::from_utf8(value)?),
+                        ),
+                        (seqno, Command::Remove { key }) => (seqno, String::from_utf8(key)?, None),
+                    })
+                })
+                .collect()
+        }
+        let set = |seqno, key: &str, value: &str| (seqno, key.to_owned(), Some(value.to_owned()));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("a".to_owned(), "1".to_owned())?;
+        store.set("b".to_owned(), "2".to_owned())?;
+        store.remove("a")?;
+        let mut batch = WriteBatch::new();
+        batch.put("c", "3").put("b", "4");
+        store.write_batch(batch)?;
+        assert_eq!(
+            changes(store.changes_since(0)?)?,
+            vec![
+                set(1, "a", "1"),
+                set(2, "b", "2"),
+                (3, "a".to_owned(), None),
+                set(4, "c", "3"),
+                set(5, "b", "4"),
+            ]
+        );
+        assert_eq!(
+            changes(store.changes_since(3)?)?,
+            vec![set(4, "c", "3"), set(5, "b", "4")]
+        );
+        assert!(store.changes_since(5)?.next().is_none());
+
+        // The merge drops the records with the highest numbers, which are
+        // still never handed out again. Checkpoints that would miss one of
+        // the dropped removes fail.
+        store.remove("c")?;
+        store.compaction()?;
+        assert!(matches!(
+            store.changes_since(5),
+            Err(Error::HistoryCompacted {
+                since: 5,
+                compacted: 6
+            })
+        ));
+        assert!(store.changes_since(6)?.next().is_none());
+        drop(store);
+
+        let store = BitCaskPlus::open(temp_dir.path())?;
+        store.set("d".to_owned(), "5".to_owned())?;
+        // Only the generation holding number 7 is read past checkpoint 6.
+        let max_seqnos: Vec<_> = store.file_stats().values().map(|s| s.max_seqno).collect();
+        assert_eq!(max_seqnos, vec![5, 7]);
+        assert!(store.changes_since(0).is_err());
+        assert_eq!(changes(store.changes_since(6)?)?, vec![set(7, "d", "5")]);
+        drop(store);
+
+        let store = BitCaskPlus::open_read_only(temp_dir.path())?;
+        assert!(store.changes_since(5).is_err());
+        assert_eq!(changes(store.changes_since(6)?)?, vec![set(7, "d", "5")]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const OPTIONS_FILE: &str = "OPTIONS";
+
+/// When appended records are forced from the page cache to disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// `sync_data` after every record. A successful `set` survives power loss.
+    SyncEveryWrite,
+    /// A background thread syncs the active file on this interval.
+    SyncEvery(Duration),
+    /// Sync once this many bytes have been appended since the last sync.
+    SyncEveryNBytes(u64),
+    /// Leave write-back to the operating system.
+    #[default]
+    OsManaged,
+}
+
+/// Settings for [`BitCaskPlus::open_with`](crate::BitCaskPlus::open_with).
+///
+/// Everything except `read_only` and `create_dir` is recorded in the store
+/// directory, so a reopen with limits the existing data may violate fails with
+/// [`Error::OptionsMismatch`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Options {
+    pub(crate) compaction_threshold: u64,
+    pub(crate) frag_threshold: u64,
+    pub(crate) dead_bytes_threshold: u64,
+    pub(crate) max_file_size: u64,
+    pub(crate) max_key_size: u64,
+    pub(crate) max_value_size: u64,
+    pub(crate) sync_policy: SyncPolicy,
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+    #[serde(skip)]
+    pub(crate) create_dir: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 1024 * 1024,
+            frag_threshold: 40,
+            dead_bytes_threshold: 128 * 1024 * 1024,
+            max_file_size: 2 * 1024 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 10 * 1024 * 1024,
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            create_dir: true,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of overwritten or deleted records that trigger a compaction.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Percentage of dead bytes above which background compaction merges a
+    /// generation.
+    pub fn frag_threshold(mut self, percent: u64) -> Self {
+        self.frag_threshold = percent;
+        self
+    }
+
+    /// Dead bytes above which background compaction merges a generation,
+    /// however small its fragmentation.
+    pub fn dead_bytes_threshold(mut self, bytes: u64) -> Self {
+        self.dead_bytes_threshold = bytes;
+        self
+    }
+
+    /// Size at which the active data file is rotated.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    pub fn max_key_size(mut self, bytes: u64) -> Self {
+        self.max_key_size = bytes;
+        self
+    }
+
+    pub fn max_value_size(mut self, bytes: u64) -> Self {
+        self.max_value_size = bytes;
+        self
+    }
+
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Open without creating, truncating or deleting any file. Writes fail
+    /// with [`Error::ReadOnly`].
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Create the store directory when it does not exist yet.
+    pub fn create_dir(mut self, create_dir: bool) -> Self {
+        self.create_dir = create_dir;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(Error::InvalidOptions(msg.to_string()));
+        if self.compaction_threshold == 0 {
+            return invalid("compaction threshold must be positive");
+        }
+        if self.frag_threshold > 100 {
+            return invalid("fragmentation threshold is a percentage");
+        }
+        if self.max_file_size <= crate::db_format::FILE_HEADER_LEN {
+            return invalid("max file size must leave room for records");
+        }
+        if self.max_key_size == 0 || self.max_key_size > u32::MAX as u64 {
+            return invalid("max key size must be between 1 and u32::MAX");
+        }
+        if self.max_value_size > u32::MAX as u64 {
+            return invalid("max value size must not exceed u32::MAX");
+        }
+        match self.sync_policy {
+            SyncPolicy::SyncEvery(interval) if interval.is_zero() => {
+                invalid("sync interval must be positive")
+            }
+            SyncPolicy::SyncEveryNBytes(0) => invalid("sync byte count must be positive"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks these options against the ones recorded in the store directory
+    /// and records them for the next open.
+    ///
+    /// A read-only handle writes nothing, so it is not held to the size
+    /// limits of the writer.
+    pub(crate) fn reconcile(&self, path: &Path) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let options_path = path.join(OPTIONS_FILE);
+        let encoded = serde_json::to_vec(self)?;
+        if let Ok(data) = fs::read(&options_path) {
+            let stored: Options = serde_json::from_slice(&data)?;
+            if self.max_key_size < stored.max_key_size {
+                return Err(Error::OptionsMismatch {
+                    name: "max_key_size",
+                    stored: stored.max_key_size,
+                    requested: self.max_key_size,
+                });
+            }
+            if self.max_value_size < stored.max_value_size {
+                return Err(Error::OptionsMismatch {
+                    name: "max_value_size",
+                    stored: stored.max_value_size,
+                    requested: self.max_value_size,
+                });
+            }
+            if data == encoded {
+                return Ok(());
+            }
+        }
+        let tmp_path = path.join(format!("{}.tmp", OPTIONS_FILE));
+        fs::write(&tmp_path, encoded)?;
+        fs::rename(&tmp_path, &options_path)?;
+        Ok(())
+    }
+}